@@ -0,0 +1,57 @@
+#![no_main]
+
+//! Reads the input as a sequence of operations and runs them against a
+//! fresh tree. Each operation is encoded as:
+//!
+//! `[op u8][key len u8][key][value len u8][value]`
+//!
+//! where `op % 3` selects insert, search or soft delete, and the value is
+//! only present for inserts. Errors are expected (oversized keys, bad
+//! UTF-8 replaced by U+FFFD, ...) and ignored; a panic is a bug.
+
+use std::path::PathBuf;
+
+use btree_rs::{btree::BTree, node::KeyValuePair};
+use libfuzzer_sys::fuzz_target;
+
+fn take<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (&len, rest) = data.split_first()?;
+    let len = (len as usize).min(rest.len());
+    let (bytes, rest) = rest.split_at(len);
+    *data = rest;
+    Some(bytes)
+}
+
+fn db_path() -> PathBuf {
+    let dir = std::env::temp_dir().join("btree-rs-fuzz").join(std::process::id().to_string());
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("db")
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut btree) = BTree::with_path(db_path(), 2) else {
+        return;
+    };
+
+    let mut data = data;
+    while let Some((&op, rest)) = data.split_first() {
+        data = rest;
+        let Some(key) = take(&mut data) else {
+            break;
+        };
+        let key = String::from_utf8_lossy(key).into_owned();
+        match op % 3 {
+            0 => {
+                let value = take(&mut data).map(String::from_utf8_lossy).unwrap_or_default();
+                let _ = btree.insert(KeyValuePair::new(key, value.into_owned()));
+            }
+            1 => {
+                let _ = btree.search(key);
+            }
+            _ => {
+                let _ = btree.soft_delete(&key);
+            }
+        }
+    }
+    let _ = btree.to_vec();
+});
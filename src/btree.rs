@@ -1,60 +1,212 @@
-use std::path::Path;
+use std::{
+  collections::{HashMap, HashSet},
+  io::{BufRead, Write},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
   error::Error,
-  node::{Key, KeyValuePair, Node, NodeType, Offset},
-  page::Page,
-  pager::Pager, wal::Wal
+  node::{Key, KeyValuePair, Node, NodeType, Offset, SplitPolicy},
+  page::{Page, PAGE_SIZE},
+  pager::Pager, wal::{Wal, WalOperation},
+  utils::{same_directory, unix_timestamp},
 };
 
-const MAX_BRANCHING_FACTOR: usize = 200;
+/// Calls `BTree::assert_sorted` on `$btree` in debug builds, and expands to
+/// nothing that runs in release builds.
+#[macro_export]
+macro_rules! btree_debug_assert_sorted {
+  ($btree:expr) => {
+    if cfg!(debug_assertions) {
+      $btree.assert_sorted();
+    }
+  };
+}
+
+// `small_btree` wins when both features are enabled, so that the features
+// stay additive. Neither cap can exceed what a full node fits in one page.
+#[cfg(feature = "small_btree")]
+const MAX_BRANCHING_FACTOR: usize = 10;
+#[cfg(all(feature = "large_btree", not(feature = "small_btree")))]
+const MAX_BRANCHING_FACTOR: usize = crate::page::MAX_BRANCHES_PER_PAGE;
+#[cfg(not(any(feature = "small_btree", feature = "large_btree")))]
+const MAX_BRANCHING_FACTOR: usize = if crate::page::MAX_BRANCHES_PER_PAGE < 200 { crate::page::MAX_BRANCHES_PER_PAGE } else { 200 };
+#[allow(dead_code)]
 const NODE_KEYS_LIMIT: usize = MAX_BRANCHING_FACTOR - 1;
 
 #[derive(Debug)]
 pub struct BTree {
-  path: &'static Path,
+  path: PathBuf,
   branches: usize,
   pager: Pager,
   wal: Wal,
+  closed: bool,
+  /// Set until the root leaf has been written, which happens on first use.
+  pending_init: bool,
+  /// Whether every page write and WAL record is synced as it is written.
+  sync_writes: bool,
 }
 
 impl BTree {
-  pub fn new(path: &'static Path, branches: usize) -> Result<Self, Error> {
-    if branches == 0 || branches > MAX_BRANCHING_FACTOR {
-      return Err(Error::UnexpectedError);
+  pub fn new(path: impl AsRef<Path>, branches: usize) -> Result<Self, Error> {
+    Self::new_with_options(path, BTreeOptions::new().with_branches(branches))
+  }
+
+  pub fn with_path(path: PathBuf, branches: usize) -> Result<Self, Error> {
+    Self::new_with_options(path, BTreeOptions::new().with_branches(branches))
+  }
+
+  /// Creates an empty tree at `path`, truncating any existing file.
+  pub fn new_with_options(path: impl AsRef<Path>, options: BTreeOptions) -> Result<Self, Error> {
+    let BTreeOptions { branches, page_size } = options;
+    check_branching_factor(branches)?;
+    if page_size != PAGE_SIZE {
+      return Err(Error::PageSizeMismatch { provided: page_size, expected: PAGE_SIZE });
     }
 
-    let mut pager = Pager::new(path)?;
-    let root = Node::new(NodeType::Leaf(vec![]), true, None);
-    let root_offset = pager.write_page(Page::try_from(&root)?)?;
+    let path = path.as_ref().to_path_buf();
+    let mut pager = Pager::new(&path)?;
+    pager.lock_exclusive()?;
+    pager.truncate()?;
     let parent_directory = path.parent().unwrap_or_else(|| Path::new("/tmp"));
-    let mut wal = Wal::new(parent_directory.to_path_buf())?;
-    wal.set_root(root_offset)?;
+    let wal = Wal::new(parent_directory.to_path_buf())?;
 
     Ok(Self {
       pager,
       path,
       branches,
       wal,
+      closed: false,
+      sync_writes: false,
+      pending_init: true,
+    })
+  }
+
+  pub fn open(path: impl AsRef<Path>, branches: usize) -> Result<Self, Error> {
+    check_branching_factor(branches)?;
+
+    let path = path.as_ref();
+    let (pager, wal) = Self::open_storage(path)?;
+
+    Ok(Self {
+      pending_init: !wal.has_root(),
+      pager,
+      path: path.to_path_buf(),
+      branches,
+      wal,
+      closed: false,
+      sync_writes: false,
+    })
+  }
+
+  /// Creates a tree at `path` holding the entries of `map`, written in key
+  /// order as a single batch.
+  pub fn from_hashmap(map: HashMap<String, String>, path: impl AsRef<Path>, branches: usize) -> Result<Self, Error> {
+    let mut btree = Self::with_path(path.as_ref().to_path_buf(), branches)?;
+    let mut entries: Vec<_> = map.into_iter().collect();
+    entries.sort_unstable();
+    let mut batch = WriteBatch::new();
+    for (key, value) in entries {
+      batch.insert(KeyValuePair::new(key, value));
+    }
+    btree.write_batch(batch)?;
+    Ok(btree)
+  }
+
+  /// Opens the data file at `path` when its WAL is lost, by scanning every
+  /// page and writing a fresh WAL that points at the root. Copy-on-write
+  /// leaves the pages of earlier roots behind, so the root pages are tried
+  /// from the highest offset down and the first one whose whole subtree can
+  /// be read is taken as current.
+  ///
+  /// Without the WAL there is no record of which roots were committed: the
+  /// pages of a write that was rolled back or cut short, e.g. a failed
+  /// `write_batch`, form a complete tree too, so its changes can come back.
+  pub fn rebuild_index(path: impl AsRef<Path>, branches: usize) -> Result<Self, Error> {
+    check_branching_factor(branches)?;
+
+    let path = path.as_ref();
+    let pager = Pager::open(path)?;
+    pager.lock_exclusive()?;
+    let root_offset = (0..pager.page_count()).rev().map(|n| Offset(n * PAGE_SIZE)).find(|offset| {
+      let is_root = pager.read_page(offset).and_then(Node::try_from).is_ok_and(|node| node.is_root);
+      is_root && is_readable_sub_tree(&pager, offset)
+    });
+
+    let parent_directory = path.parent().unwrap_or_else(|| Path::new("/tmp"));
+    let mut wal = Wal::new(parent_directory.to_path_buf())?;
+    match root_offset {
+      Some(root_offset) => wal.reset(root_offset)?,
+      None if pager.page_count() > 0 => return Err(Error::TreeCorrupted(String::from("no readable root page found"))),
+      None => {}
+    }
+
+    Ok(Self {
+      pending_init: !wal.has_root(),
+      pager,
+      path: path.to_path_buf(),
+      branches,
+      wal,
+      closed: false,
+      sync_writes: false,
     })
   }
 
+  /// Copies the data file to `dest_path` and the WAL next to it, while the
+  /// tree stays open. Returns the number of bytes copied. Nothing else can
+  /// write through `&mut self` meanwhile, so the copy is consistent. Fails
+  /// if `dest_path` is in the tree's own directory, where the copy would
+  /// overwrite the live WAL, or the data file itself.
+  pub fn backup_to(&mut self, dest_path: impl AsRef<Path>) -> Result<u64, Error> {
+    let dest_path = dest_path.as_ref();
+    let dest_directory = parent_directory(dest_path)?;
+    if same_directory(&dest_directory, &parent_directory(&self.path)?) {
+      return Err(Error::WalDirectoryInUse(dest_directory));
+    }
+    Ok(self.pager.backup_to(dest_path)? + self.wal.backup_to(dest_directory)?)
+  }
+
+  /// Switches the tree over to the data file at `new_path` and its WAL. The
+  /// new file is opened, locked and checked first, so on error the tree
+  /// keeps using its current file. The old file is checkpointed and
+  /// unlocked before it is dropped.
+  ///
+  /// The WAL is named after its directory, not its data file, so two data
+  /// files in one directory would share a log. `new_path` must therefore be
+  /// in a different directory from the current file.
+  pub fn hot_reload(&mut self, new_path: impl AsRef<Path>) -> Result<(), Error> {
+    let new_path = new_path.as_ref();
+    let new_directory = parent_directory(new_path)?;
+    if same_directory(&new_directory, &parent_directory(&self.path)?) {
+      return Err(Error::WalDirectoryInUse(new_directory));
+    }
+    let (pager, wal) = Self::open_storage(new_path)?;
+
+    self.rotate_wal()?;
+    self.pager.unlock()?;
+    self.pending_init = !wal.has_root();
+    self.pager = pager;
+    self.wal = wal;
+    self.enable_fsync_on_write(self.sync_writes);
+    self.path = new_path.to_path_buf();
+    Ok(())
+  }
+
   pub fn insert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
-    let root_offset = self.wal.get_root()?;
+    let root_offset = self.root_offset()?;
     let root_page = self.pager.get_page(&root_offset)?;
     let new_root_offset: Offset;
     let mut new_root: Node;
 
     let mut root = Node::try_from(root_page)?;
 
-    println!("root: {:?} - is node full? {}", root, self.is_node_full(&root)?);
-
     if self.is_node_full(&root)? {
         new_root = Node::new(NodeType::Internal(vec![], vec![]), true, None);
         new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
         root.parent_offset = Some(new_root_offset.clone());
         root.is_root = false;
-        let (median, sibling) = root.split(self.branches)?;
+        let (median, sibling) = root.split(Node::optimal_split_idx(self.branches, SplitPolicy::Balanced))?;
         let old_root_offset = self.pager.write_page(Page::try_from(&root)?)?;
         let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
         new_root.node_type = NodeType::Internal(vec![old_root_offset, sibling_offset], vec![median]);
@@ -64,141 +216,3546 @@ impl BTree {
         new_root_offset = self.pager.write_page(Page::try_from(&new_root)?)?;
     }
 
-    self.insert_non_full(&mut new_root, new_root_offset.clone(), kv)?;
+    self.insert_non_full(&mut new_root, new_root_offset.clone(), kv.clone())?;
+    self.wal.append_operation(WalOperation::Insert(kv))?;
     self.wal.set_root(new_root_offset)
   }
 
-  pub fn search(&mut self, key: String) -> Result<KeyValuePair, Error> {
+  /// Inserts `kv`, or replaces the existing entry with the same key.
+  pub fn upsert(&mut self, kv: KeyValuePair) -> Result<(), Error> {
+    let root_offset = self.root_offset()?;
+    let replacement = kv.clone();
+    let root = self.modify_leaf_for_key(&root_offset, &kv.key, |pairs| {
+      match pairs.binary_search(&replacement) {
+        Ok(idx) => {
+          pairs[idx] = replacement;
+          true
+        }
+        Err(_) => false,
+      }
+    })?;
+    match root {
+      Some(root) => {
+        self.wal.append_operation(WalOperation::Update { key: kv.key, new_value: kv.value })?;
+        self.write_root(root)
+      }
+      None => self.insert(kv),
+    }
+  }
+
+  /// Like `upsert`, but returns the entry that was replaced, or `None` when
+  /// `kv.key` was not present (or only as a deleted or expired entry).
+  pub fn replace(&mut self, kv: KeyValuePair) -> Result<Option<KeyValuePair>, Error> {
+    let old = self.search(kv.key.clone())?;
+    self.upsert(kv)?;
+    Ok(old)
+  }
+
+  /// Inserts `kv` so that it stops being visible once the Unix timestamp
+  /// `expires_at` has passed.
+  pub fn insert_with_ttl(&mut self, mut kv: KeyValuePair, expires_at: u64) -> Result<(), Error> {
+    kv.expires_at = Some(expires_at);
+    self.insert(kv)
+  }
+
+  /// Looks up `key`, returning `Ok(None)` when it is absent, deleted or expired.
+  pub fn search(&mut self, key: String) -> Result<Option<KeyValuePair>, Error> {
+    if self.pending_init {
+      return Ok(None);
+    }
     let root_offset = self.wal.get_root()?;
     let root_page = self.pager.get_page(&root_offset)?;
     let root = Node::try_from(root_page)?;
-    self.search_node(root, key)
+    let now = unix_timestamp();
+    Ok(self.search_node(root, key)?.filter(|kv| !kv.deleted && !kv.is_expired(now)))
   }
 
-  pub fn print(&mut self) -> Result<(), Error> {
-    println!("");
-    let root_offset = self.wal.get_root()?;
-    self.print_sub_tree(String::from(""), root_offset)
+  /// Returns the value stored under `key`, or `default` if there is none.
+  pub fn get_or_default(&mut self, key: &str, default: &str) -> Result<String, Error> {
+    Ok(self.search(key.to_string())?.map_or_else(|| default.to_string(), |kv| kv.value))
   }
 
-  fn insert_non_full(&mut self, node: &mut Node, node_offset: Offset, kv: KeyValuePair) -> Result<(), Error> {
-    match &mut node.node_type {
-        NodeType::Leaf(ref mut pairs) => {
-            let idx = pairs.binary_search(&kv).unwrap_or_else(|x| x);
-            pairs.insert(idx, kv);
-            self.pager.write_page_at_offset(Page::try_from(&*node)?, &node_offset)
+  /// Whether every key in `keys` is present. Stops at the first missing
+  /// key, and is true for an empty `keys`.
+  pub fn contains_all(&self, keys: &[&str]) -> Result<bool, Error> {
+    for key in keys {
+      if self.get(key)?.is_none() {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Whether any key in `keys` is present. Stops at the first key found,
+  /// and is false for an empty `keys`.
+  pub fn contains_any(&self, keys: &[&str]) -> Result<bool, Error> {
+    for key in keys {
+      if self.get(key)?.is_some() {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+
+  /// Applies every operation in `batch`, or none of them. The operations are
+  /// logged between `Begin` and `Commit` WAL records; if one fails, the root
+  /// from before the batch is restored and the error is returned.
+  pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+    let root_offset = self.root_offset()?;
+    self.wal.append_operation(WalOperation::Begin)?;
+    let applied = batch.operations.into_iter().try_for_each(|op| match op {
+      BatchOperation::Insert(kv) => self.insert(kv),
+      BatchOperation::Delete(key) => self.soft_delete(&key).map(|_| ()),
+    });
+    match applied {
+      Ok(()) => self.wal.append_operation(WalOperation::Commit),
+      Err(e) => {
+        self.wal.append_operation(WalOperation::Rollback)?;
+        self.wal.set_root(root_offset)?;
+        Err(e)
+      }
+    }
+  }
+
+  /// Same as `search`, but only borrows the tree immutably.
+  pub fn get(&self, key: &str) -> Result<Option<KeyValuePair>, Error> {
+    if self.pending_init {
+      return Ok(None);
+    }
+    let mut node = Node::try_from(self.pager.read_page(&self.wal.get_root()?)?)?;
+    loop {
+      let child_idx = node.find_child_index(key);
+      match node.node_type {
+        NodeType::Internal(children, _) => {
+          let child_offset = children.get(child_idx?).ok_or(Error::UnexpectedError)?;
+          node = Node::try_from(self.pager.read_page(child_offset)?)?;
         }
-        NodeType::Internal(ref mut children, ref mut keys) => {
-            let idx = keys.binary_search(&Key(kv.key.clone())).unwrap_or_else(|x| x);
-            let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
-            let child_page = self.pager.get_page(&child_offset)?;
-            let mut child = Node::try_from(child_page)?;
-            let new_child_offset = self.pager.write_page(Page::try_from(&child)?)?;
-            children[idx] = new_child_offset.to_owned();
-            if self.is_node_full(&child)? {
-                let (median, mut sibling) = child.split(self.branches)?;
-                self.pager.write_page_at_offset(Page::try_from(&child)?, &new_child_offset)?;
-                let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
-                children.insert(idx + 1, sibling_offset.clone());
-                keys.insert(idx, median.clone());
-                self.pager.write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                if kv.key <= median.0 {
-                    self.insert_non_full(&mut child, new_child_offset, kv)
-                } else {
-                    self.insert_non_full(&mut sibling, sibling_offset, kv)
-                }
-            } else {
-                self.pager.write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
-                self.insert_non_full(&mut child, new_child_offset, kv)
-            }
+        NodeType::Leaf(mut pairs) => {
+          let now = unix_timestamp();
+          return Ok(pairs
+            .binary_search_by(|pair| pair.key.as_str().cmp(key))
+            .ok()
+            .map(|idx| pairs.swap_remove(idx))
+            .filter(|pair| !pair.deleted && !pair.is_expired(now)));
         }
-        NodeType::Unexpected => Err(Error::UnexpectedError),
+        NodeType::Unexpected => return Err(Error::UnexpectedError),
+      }
     }
   }
 
-  fn is_node_full(&self, node: &Node) -> Result<bool, Error> {
-    match &node.node_type {
-      NodeType::Leaf(pairs) => Ok(pairs.len() == (2 * self.branches - 1)),
-      NodeType::Internal(_, keys) => Ok(keys.len() == (2 * self.branches - 1)),
-      NodeType::Unexpected => Err(Error::UnexpectedError)
+  /// Returns the smallest visible key strictly greater than `key`.
+  pub fn first_key_after(&mut self, key: &str) -> Result<Option<String>, Error> {
+    if self.pending_init {
+      return Ok(None);
     }
+    let root_offset = self.wal.get_root()?;
+    self.key_after(&root_offset, key, unix_timestamp())
   }
 
-  fn search_node(&mut self, node: Node, search: String) -> Result<KeyValuePair, Error> {
-    match node.node_type {
-        NodeType::Internal(children, keys) => {
-            let idx = keys.binary_search(&Key(search.clone())).unwrap_or_else(|x| x);
-            let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?;
-            let page = self.pager.get_page(child_offset)?;
-            let child_node = Node::try_from(page)?;
-            self.search_node(child_node, search)
-        } 
-        NodeType::Leaf(pairs) => {
-            if let Ok(idx) = pairs.binary_search_by_key(&search, |pair| pair.key.clone()) {
-                return Ok(pairs[idx].clone());
-            }
-            Err(Error::KeyNotFound)
-        }
-        NodeType::Unexpected => Err(Error::UnexpectedError),
+  /// Returns the greatest visible key strictly less than `key`.
+  pub fn last_key_before(&mut self, key: &str) -> Result<Option<String>, Error> {
+    if self.pending_init {
+      return Ok(None);
     }
+    let root_offset = self.wal.get_root()?;
+    self.key_before(&root_offset, key, unix_timestamp())
   }
 
-  fn print_sub_tree(&mut self, prefix: String, offset: Offset) -> Result<(), Error> {
-    println!("{}Node at offset: {}", prefix, offset.0);
-    let curr_prefix = format!("{}|->", prefix);
-    let page = self.pager.get_page(&offset)?;
-    let node = Node::try_from(page)?;
-    match node.node_type {
-        NodeType::Internal(children, keys) => {
-            println!("{}Keys: {:?}", curr_prefix, keys);
-            println!("{}Children: {:?}", curr_prefix, children);
-            let child_prefix = format!("{}   |  ", prefix);
-            for child_offset in children {
-                self.print_sub_tree(child_prefix.clone(), child_offset)?;
-            }
-            Ok(())
+  /// Marks the entry for `key` as deleted without removing it from its page.
+  /// The tombstone is hidden from `search` and dropped by `compact`. Returns
+  /// whether there was a live entry to delete.
+  pub fn soft_delete(&mut self, key: &str) -> Result<bool, Error> {
+    let root_offset = self.root_offset()?;
+    let root = self.modify_leaf_for_key(&root_offset, key, |pairs| {
+      match pairs.binary_search_by(|pair| pair.key.as_str().cmp(key)) {
+        Ok(idx) if !pairs[idx].deleted => {
+          pairs[idx].deleted = true;
+          true
         }
-        NodeType::Leaf(pairs) => {
-            println!("{}Key value pairs: {:?}", curr_prefix, pairs);
-            Ok(())
+        _ => false,
+      }
+    })?;
+    let Some(root) = root else {
+      return Ok(false);
+    };
+    self.wal.append_operation(WalOperation::Delete(key.to_string()))?;
+    self.write_root(root)?;
+    Ok(true)
+  }
+
+  /// Returns an iterator that removes entries from the front of the tree as
+  /// it yields them, in ascending key order. Entries are taken out a leaf at
+  /// a time; like `Vec::drain`, dropping the iterator early still empties
+  /// the tree.
+  pub fn drain(&mut self) -> BTreeDrain<'_> {
+    BTreeDrain {
+      btree: self,
+      buffer: Vec::new().into_iter(),
+      done: false,
+    }
+  }
+
+  /// Removes and returns the entry with the smallest key.
+  pub fn pop_first(&mut self) -> Result<Option<KeyValuePair>, Error> {
+    self.pop_edge(true)
+  }
+
+  /// Removes and returns the entry with the largest key.
+  pub fn pop_last(&mut self) -> Result<Option<KeyValuePair>, Error> {
+    self.pop_edge(false)
+  }
+
+  /// Physically removes soft-deleted entries and returns how many were removed.
+  pub fn compact(&mut self) -> Result<usize, Error> {
+    let root_offset = self.root_offset()?;
+    let (root, removed) = self.remove_pairs_in_sub_tree(&root_offset, &|_, _| true, &mut |pair| pair.deleted)?;
+    if let Some(root) = root {
+      self.write_root(root)?;
+      self.merge_leaves()?;
+    }
+    Ok(removed)
+  }
+
+  /// Merges every pair of adjacent sibling leaves whose entries fit in one
+  /// node, removing the separator between them, and then fixes any internal
+  /// node left underflowing. Returns the number of merges.
+  pub fn merge_leaves(&mut self) -> Result<usize, Error> {
+    let root_offset = self.root_offset()?;
+    let (root, merges) = self.merge_leaves_in_sub_tree(&root_offset)?;
+    if let Some(root) = root {
+      self.write_root(root)?;
+    }
+    Ok(merges)
+  }
+
+  /// Physically removes every expired entry and returns how many were removed.
+  pub fn purge_expired(&mut self) -> Result<usize, Error> {
+    let now = unix_timestamp();
+    let root_offset = self.root_offset()?;
+    let (root, removed) = self.remove_pairs_in_sub_tree(&root_offset, &|_, _| true, &mut |pair| pair.is_expired(now))?;
+    if let Some(root) = root {
+      self.write_root(root)?;
+    }
+    Ok(removed)
+  }
+
+  /// Smallest height a tree with `branches` can hold `n` entries in. Each
+  /// node takes at most `2 * branches - 1` keys, so a tree of height `h`
+  /// holds at most `(2 * branches - 1) * (2 * branches)^(h - 1)` entries.
+  /// Panics if `branches` is 0.
+  pub const fn estimate_height_for_count(n: usize, branches: usize) -> usize {
+    assert!(branches > 0, "branches must be at least 1");
+    let fanout = 2 * branches;
+    let mut capacity = fanout - 1;
+    let mut height = 1;
+    while capacity < n {
+      capacity = capacity.saturating_mul(fanout);
+      height += 1;
+    }
+    height
+  }
+
+  /// Smallest branching factor, within `2..=MAX_BRANCHING_FACTOR`, that
+  /// keeps any tree of `entry_count` entries at most `max_height` high. A
+  /// tree one level higher needs at least `2 * (b - 1) * b^(max_height - 1)`
+  /// entries, as every node but the root is at least half full, so the
+  /// factor is the first `b` for which that exceeds `entry_count`. Returns
+  /// `MAX_BRANCHING_FACTOR` if no factor is large enough.
+  pub fn suggest_branching_factor(entry_count: usize, max_height: usize) -> usize {
+    (2..MAX_BRANCHING_FACTOR)
+      .find(|&branches| {
+        let min_entries_one_level_higher = (1..max_height.max(1))
+          .fold(2 * (branches - 1), |entries, _| entries.saturating_mul(branches));
+        min_entries_one_level_higher > entry_count
+      })
+      .unwrap_or(MAX_BRANCHING_FACTOR)
+  }
+
+  /// `suggest_branching_factor` for the pairs currently stored, tombstones
+  /// included since they take up room until compacted.
+  pub fn suggest_rebranching(&mut self, max_height: usize) -> Result<usize, Error> {
+    let (_, stored) = self.count_pairs()?;
+    Ok(Self::suggest_branching_factor(stored, max_height))
+  }
+
+  pub fn tree_height(&mut self) -> Result<usize, Error> {
+    let root_offset = self.root_offset()?;
+    Pager::node_height(&mut self.pager, root_offset)
+  }
+
+  /// Returns how many `(internal, leaf)` nodes are reachable from the root.
+  pub fn node_count(&mut self) -> Result<(usize, usize), Error> {
+    let root_offset = self.root_offset()?;
+    let (mut internal, mut leaf) = (0, 0);
+    self.visit_nodes(&root_offset, 0, &mut |_, node, _| {
+      if node.is_leaf() {
+        leaf += 1;
+      } else {
+        internal += 1;
+      }
+    })?;
+    Ok((internal, leaf))
+  }
+
+  /// Calls `f` with every node reachable from the root and its depth, in
+  /// depth-first pre-order. The root is at depth 0.
+  pub fn walk_with_depth<F>(&mut self, mut f: F) -> Result<(), Error>
+  where
+    F: FnMut(&Node, usize),
+  {
+    let root_offset = self.root_offset()?;
+    self.visit_nodes(&root_offset, 0, &mut |_, node, depth| f(node, depth))
+  }
+
+  /// Counts the soft-deleted pairs still stored in the leaves, i.e. the
+  /// pairs the next `compact` would remove.
+  pub fn tombstone_count(&mut self) -> Result<usize, Error> {
+    Ok(self.count_pairs()?.0)
+  }
+
+  /// Share of the stored pairs that are tombstones, or 0 for an empty tree.
+  pub fn tombstone_ratio(&mut self) -> Result<f64, Error> {
+    let (tombstones, total) = self.count_pairs()?;
+    if total == 0 {
+      return Ok(0.0);
+    }
+    Ok(tombstones as f64 / total as f64)
+  }
+
+  /// Returns every key stored more than once, tombstones included, in key
+  /// order. Leaves are visited in key order, so a duplicate shows up as two
+  /// consecutive pairs with the same key, even across a leaf boundary.
+  pub fn find_all_duplicates(&mut self) -> Result<Vec<String>, Error> {
+    let root_offset = self.root_offset()?;
+    let mut duplicates: Vec<String> = vec![];
+    let mut previous: Option<String> = None;
+    self.visit_nodes(&root_offset, 0, &mut |_, node, _| {
+      let NodeType::Leaf(pairs) = &node.node_type else {
+        return;
+      };
+      for pair in pairs {
+        if previous.as_ref() == Some(&pair.key) && duplicates.last() != Some(&pair.key) {
+          duplicates.push(pair.key.clone());
         }
-        NodeType::Unexpected => Err(Error::UnexpectedError),
+        previous = Some(pair.key.clone());
+      }
+    })?;
+    Ok(duplicates)
+  }
+
+  /// Returns the offset of every reachable page, in depth-first pre-order.
+  pub fn depth_first_offsets(&mut self) -> Result<Vec<Offset>, Error> {
+    let root_offset = self.root_offset()?;
+    let mut offsets = Vec::new();
+    self.visit_nodes(&root_offset, 0, &mut |offset, _, _| offsets.push(offset.clone()))?;
+    Ok(offsets)
+  }
+
+  /// Whether every page in the data file is reachable from the root. There
+  /// is no free list, and copy-on-write leaves each replaced page behind, so
+  /// this only holds for a file no write has superseded a page of yet.
+  pub fn all_offsets_reachable(&mut self) -> Result<bool, Error> {
+    let reachable: HashSet<usize> = self.depth_first_offsets()?.into_iter().map(|offset| offset.0).collect();
+    Ok((0..self.pager.page_count()).all(|n| reachable.contains(&(n * PAGE_SIZE))))
+  }
+
+  /// Returns how full the leaves are on average, as a share of the
+  /// `2 * branches - 1` pairs a leaf can hold.
+  pub fn average_fill_factor(&mut self) -> Result<f64, Error> {
+    Ok(self.fill_stats()?.mean)
+  }
+
+  /// Returns the mean, minimum and maximum leaf fill factor. Tombstones
+  /// count towards the fill since they still take up room in the page.
+  pub fn fill_stats(&mut self) -> Result<BTreeFillStats, Error> {
+    let root_offset = self.root_offset()?;
+    let max_pairs = (2 * self.branches - 1) as f64;
+    let mut fills = Vec::new();
+    self.visit_nodes(&root_offset, 0, &mut |_, node, _| {
+      if let NodeType::Leaf(pairs) = &node.node_type {
+        fills.push(pairs.len() as f64 / max_pairs);
+      }
+    })?;
+    Ok(BTreeFillStats {
+      mean: fills.iter().sum::<f64>() / fills.len() as f64,
+      min: fills.iter().copied().fold(f64::INFINITY, f64::min),
+      max: fills.iter().copied().fold(0.0, f64::max),
+    })
+  }
+
+  /// Counts the leaves per tenth of fill, as `(lower_bound_percent, count)`
+  /// for 0, 10, ..., 90. Full leaves land in the 90 bucket. Like
+  /// `fill_stats`, tombstones count towards the fill.
+  pub fn page_usage_histogram(&mut self) -> Result<Vec<(usize, usize)>, Error> {
+    let root_offset = self.root_offset()?;
+    let max_pairs = 2 * self.branches - 1;
+    let mut counts = [0; 10];
+    self.visit_nodes(&root_offset, 0, &mut |_, node, _| {
+      if let NodeType::Leaf(pairs) = &node.node_type {
+        counts[(pairs.len() * 100 / max_pairs / 10).min(9)] += 1;
+      }
+    })?;
+    Ok(counts.into_iter().enumerate().map(|(bucket, count)| (bucket * 10, count)).collect())
+  }
+
+  /// Length in bytes of the longest visible key, or 0 for an empty tree.
+  pub fn max_key_length_bytes(&mut self) -> Result<usize, Error> {
+    let mut max = 0;
+    let root_offset = self.root_offset()?;
+    self.visit_pairs(&root_offset, &mut |pair| {
+      max = max.max(pair.key.len());
+      Ok(())
+    })?;
+    Ok(max)
+  }
+
+  /// Length in bytes of the longest visible value, or 0 for an empty tree.
+  pub fn max_value_length_bytes(&mut self) -> Result<usize, Error> {
+    let mut max = 0;
+    let root_offset = self.root_offset()?;
+    self.visit_pairs(&root_offset, &mut |pair| {
+      max = max.max(pair.value.len());
+      Ok(())
+    })?;
+    Ok(max)
+  }
+
+  /// Returns the offset of the leaf page that holds `key`, or would hold it.
+  pub fn get_page_for_key(&mut self, key: &str) -> Result<Offset, Error> {
+    let mut offset = self.root_offset()?;
+    loop {
+      let node = Node::try_from(self.pager.get_page(&offset)?)?;
+      let NodeType::Internal(children, _) = &node.node_type else {
+        return Ok(offset);
+      };
+      offset = children.get(node.find_child_index(key)?).ok_or(Error::UnexpectedError)?.clone();
     }
   }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+  /// Returns the offset of the deepest node whose subtree holds both keys,
+  /// which is the leaf itself when they share one.
+  pub fn lowest_common_ancestor(&mut self, key1: &str, key2: &str) -> Result<Offset, Error> {
+    let mut offset = self.root_offset()?;
+    loop {
+      let node = Node::try_from(self.pager.get_page(&offset)?)?;
+      let NodeType::Internal(children, _) = &node.node_type else {
+        return Ok(offset);
+      };
+      let idx = node.find_child_index(key1)?;
+      if idx != node.find_child_index(key2)? {
+        return Ok(offset);
+      }
+      offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
+    }
+  }
 
-    #[test]
-    fn should_create_new_btree() {
-        let path = Path::new("/tmp/db");
-        let branches = 10;
+  /// Loads the current root node, writing the empty root leaf first if
+  /// nothing has been written yet.
+  pub fn root_node(&mut self) -> Result<Node, Error> {
+    let root_offset = self.root_offset()?;
+    self.node_at(root_offset)
+  }
 
-        let btree = BTree::new(path, branches).unwrap();
+  /// Loads the node stored at `offset`. Offsets of superseded pages load
+  /// too, as the tree never reuses them.
+  pub fn node_at(&self, offset: Offset) -> Result<Node, Error> {
+    Node::try_from(self.pager.read_page(&offset)?)
+  }
 
-        assert_eq!(btree.branches, branches);
-        assert_eq!(btree.path, path);
+  /// Decodes the leaf page at `offset` without walking the tree. Tombstones
+  /// and expired pairs are returned as stored.
+  #[doc(hidden)]
+  pub fn read_leaf_at(&self, offset: Offset) -> Result<Vec<KeyValuePair>, Error> {
+    match self.node_at(offset)?.node_type {
+      NodeType::Leaf(pairs) => Ok(pairs),
+      _ => Err(Error::UnexpectedError),
     }
+  }
 
+  /// Decodes the separator keys of the internal page at `offset`.
+  #[doc(hidden)]
+  pub fn read_internal_keys_at(&self, offset: Offset) -> Result<Vec<Key>, Error> {
+    match self.node_at(offset)?.node_type {
+      NodeType::Internal(_, keys) => Ok(keys),
+      _ => Err(Error::UnexpectedError),
+    }
+  }
 
-  #[test]
-    fn should_insert_new_node_with_root_not_full() -> Result<(), Error> {
-        let mut btree = BTree::new(Path::new("/tmp/db"), 2)?;
-        btree.insert(KeyValuePair::new(String::from("a"), String::from("testing")))?;
-        btree.insert(KeyValuePair::new(String::from("j"), String::from("this")))?;
-        btree.insert(KeyValuePair::new(String::from("i"), String::from("other")))?;
-        btree.insert(KeyValuePair::new(String::from("m"), String::from("insertion")))?;
-        btree.insert(KeyValuePair::new(String::from("b"), String::from("other")))?;
-        btree.insert(KeyValuePair::new(String::from("n"), String::from("lol")))?;
-        btree.insert(KeyValuePair::new(String::from("ab"), String::from("value")))?;
+  /// Collects the visible pairs of every leaf page whose offset falls in
+  /// `[from_offset, to_offset)`, reading pages in file order instead of
+  /// walking from the root. Internal pages are skipped. Pages superseded by
+  /// later writes are still in the file, so a key can appear more than once
+  /// and with an older value than the tree holds.
+  pub fn scan_between_pages(&self, from_offset: Offset, to_offset: Offset) -> Result<Vec<KeyValuePair>, Error> {
+    let now = unix_timestamp();
+    let end = (to_offset.0 / PAGE_SIZE).min(self.pager.page_count());
+    let mut pairs = vec![];
+    for n in from_offset.0 / PAGE_SIZE..end {
+      if let NodeType::Leaf(leaf) = self.node_at(Offset(n * PAGE_SIZE))?.node_type {
+        pairs.extend(leaf.into_iter().filter(|kv| !kv.deleted && !kv.is_expired(now)));
+      }
+    }
+    Ok(pairs)
+  }
 
-        let _ = btree.print();
+  /// Makes everything written so far durable and shrinks the WAL to the
+  /// current root: syncs the data file and the log, then rewrites the log as
+  /// a `SetRoot` and a `Checkpoint` and syncs it again. There is no page
+  /// cache, so there are no dirty pages to write out first.
+  pub fn flush_and_checkpoint(&mut self) -> Result<(), Error> {
+    self.flush()?;
+    self.rotate_wal()
+  }
 
-        let kv = btree.search(String::from("a"));
-        assert_eq!(kv.unwrap(), KeyValuePair::new(String::from("a"), String::from("testing")));
+  /// Syncs the data file and replaces the WAL with a single entry for the
+  /// current root, so the log does not grow without bound.
+  pub fn rotate_wal(&mut self) -> Result<(), Error> {
+    if self.pending_init {
+      return Ok(());
+    }
+    let root_offset = self.wal.get_root()?;
+    self.pager.sync()?;
+    self.wal.reset(root_offset)
+  }
+
+  /// Walks the whole tree bottom-up, borrowing from or merging with siblings
+  /// wherever a node has fewer than `branches - 1` keys, e.g. after a write
+  /// sequence was interrupted. Every touched node is written to a new page.
+  pub fn rebalance(&mut self) -> Result<(), Error> {
+    let root_offset = self.root_offset()?;
+    let (root, changed) = self.rebalance_sub_tree(&root_offset)?;
+    if changed {
+      self.write_root(root)?;
+    }
+    Ok(())
+  }
+
+  /// Counts the visible entries whose key starts with `prefix`, descending
+  /// only into the children whose key range can hold such keys.
+  pub fn keys_with_prefix_count(&mut self, prefix: &str) -> Result<usize, Error> {
+    if self.pending_init {
+      return Ok(0);
+    }
+    let root_offset = self.wal.get_root()?;
+    self.count_prefix_in_sub_tree(&root_offset, prefix, unix_timestamp())
+  }
+
+  /// Removes every entry whose key starts with `prefix` and returns how many
+  /// were removed. Subtrees whose separators rule out the prefix are skipped.
+  pub fn prefix_delete(&mut self, prefix: &str) -> Result<usize, Error> {
+    let root_offset = self.root_offset()?;
+    let in_range = |lower: Option<&Key>, upper: Option<&Key>| prefix_in_range(prefix, lower, upper);
+    let (root, removed) = self.remove_pairs_in_sub_tree(&root_offset, &in_range, &mut |pair| pair.key.starts_with(prefix))?;
+    if let Some(root) = root {
+      self.write_root(root)?;
+    }
+    Ok(removed)
+  }
+
+  /// Syncs the data file, checkpoints the WAL and releases the file lock.
+  /// Dropping a `BTree` does the same on a best-effort basis.
+  pub fn close(mut self) -> Result<(), Error> {
+    self.shutdown()
+  }
+
+  /// Syncs the data file and the WAL after every write when `enabled`, so
+  /// each operation is on disk when it returns. Off by default, in which
+  /// case writes are only synced by `flush`, `rotate_wal` and `close`.
+  pub fn enable_fsync_on_write(&mut self, enabled: bool) {
+    self.sync_writes = enabled;
+    self.pager.set_sync_writes(enabled);
+    self.wal.set_sync_writes(enabled);
+  }
+
+  /// Syncs the data file and then the WAL.
+  pub fn flush(&mut self) -> Result<(), Error> {
+    self.pager.sync()?;
+    self.wal.sync()
+  }
+
+  /// The pager over the data file, for tooling that inspects it directly.
+  pub fn pager(&self) -> &Pager {
+    &self.pager
+  }
+
+  /// The write-ahead log, for tooling that inspects it directly.
+  pub fn wal(&self) -> &Wal {
+    &self.wal
+  }
+
+  /// Returns a lazy iterator over every visible entry in key order. It only
+  /// keeps the current leaf and the path down to it in memory.
+  pub fn iter(&self) -> Result<BTreeIter<'_>, Error> {
+    self.cursor_from_key("")
+  }
+
+  /// Returns the `n`th visible entry in key order, counting from 0, or
+  /// `None` if there are not that many. Walks the `n` entries before it, one
+  /// leaf at a time, without collecting the tree.
+  pub fn nth_key(&self, n: usize) -> Result<Option<KeyValuePair>, Error> {
+    self.iter()?.nth(n).transpose()
+  }
+
+  /// Returns a lazy iterator over the visible entries with keys in
+  /// `[start, end)`, in key order. It reads through `&self`, so several
+  /// range iterators over the same tree can be alive at once.
+  pub fn scan_range_iter(&self, start: &str, end: &str) -> Result<BTreeRangeIter<'_>, Error> {
+    let mut iter = BTreeRangeIter {
+      btree: self,
+      end: Some(end.to_string()),
+      path: vec![],
+      leaf: Vec::new().into_iter(),
+      now: unix_timestamp(),
+    };
+    if !self.pending_init && start < end {
+      iter.seek(self.wal.get_root()?, start)?;
+    }
+    Ok(iter)
+  }
+
+  /// Returns a lazy iterator positioned at the first visible entry with a
+  /// key `>= key`, running to the end of the tree. It descends from the
+  /// root once, straight to the leaf `key` belongs to.
+  pub fn cursor_from_key(&self, key: &str) -> Result<BTreeRangeIter<'_>, Error> {
+    let mut iter = BTreeRangeIter {
+      btree: self,
+      end: None,
+      path: vec![],
+      leaf: Vec::new().into_iter(),
+      now: unix_timestamp(),
+    };
+    if !self.pending_init {
+      iter.seek(self.wal.get_root()?, key)?;
+    }
+    Ok(iter)
+  }
+
+  /// Returns every visible entry that satisfies `predicate`, in key order.
+  /// Scans every leaf, one at a time.
+  pub fn search_by<F>(&mut self, predicate: F) -> Result<Vec<KeyValuePair>, Error>
+  where
+    F: Fn(&KeyValuePair) -> bool,
+  {
+    let mut matches = Vec::new();
+    for kv in self.iter()? {
+      let kv = kv?;
+      if predicate(&kv) {
+        matches.push(kv);
+      }
+    }
+    Ok(matches)
+  }
+
+  /// Counts the visible entries that satisfy `predicate` without collecting them.
+  pub fn count_by<F>(&mut self, predicate: F) -> Result<usize, Error>
+  where
+    F: Fn(&KeyValuePair) -> bool,
+  {
+    let mut count = 0;
+    for kv in self.iter()? {
+      if predicate(&kv?) {
+        count += 1;
+      }
+    }
+    Ok(count)
+  }
+
+  /// Groups the visible entries by the part of their key before the first
+  /// `delimiter`. Keys without the delimiter end up under `""`.
+  pub fn group_by_prefix(&mut self, delimiter: char) -> Result<HashMap<String, Vec<KeyValuePair>>, Error> {
+    let mut groups: HashMap<String, Vec<KeyValuePair>> = HashMap::new();
+    for kv in self.iter()? {
+      let kv = kv?;
+      let prefix = kv.key.split_once(delimiter).map_or("", |(prefix, _)| prefix).to_string();
+      groups.entry(prefix).or_default().push(kv);
+    }
+    Ok(groups)
+  }
+
+  /// Calls `f` with every visible entry in key order, stopping at the first
+  /// error it returns.
+  pub fn for_each<F>(&mut self, mut f: F) -> Result<(), Error>
+  where
+    F: FnMut(&KeyValuePair) -> Result<(), Error>,
+  {
+    let root_offset = self.root_offset()?;
+    self.visit_pairs(&root_offset, &mut |kv| f(&kv))
+  }
+
+  /// Replaces the value of every visible entry with `f(key, value)`. Only
+  /// the leaves with a changed value, and the nodes above them, are
+  /// rewritten. Returns the number of entries whose value changed.
+  pub fn map_values<F>(&mut self, f: F) -> Result<usize, Error>
+  where
+    F: Fn(&str, String) -> String,
+  {
+    let root_offset = self.root_offset()?;
+    let mut updates = vec![];
+    let Some(root) = self.map_sub_tree(&root_offset, &|_, _| true, &f, unix_timestamp(), &mut updates)? else {
+      return Ok(0);
+    };
+    self.write_updates(root, updates)
+  }
+
+  /// Replaces the value of every visible entry with a key in `[start, end)`
+  /// with `f(key, value)`, like `map_values`. Subtrees outside the range are
+  /// not read. Returns the number of entries whose value changed.
+  pub fn update_range<F>(&mut self, start: &str, end: &str, f: F) -> Result<usize, Error>
+  where
+    F: Fn(&str, String) -> String,
+  {
+    let root_offset = self.root_offset()?;
+    let in_range = |lower: Option<&Key>, upper: Option<&Key>| {
+      let below = upper.is_some_and(|Key(upper)| upper.as_str() < start);
+      let above = lower.is_some_and(|Key(lower)| lower.as_str() >= end);
+      !below && !above
+    };
+    let update = |key: &str, value: String| if (start..end).contains(&key) { f(key, value) } else { value };
+    let mut updates = vec![];
+    let Some(root) = self.map_sub_tree(&root_offset, &in_range, &update, unix_timestamp(), &mut updates)? else {
+      return Ok(0);
+    };
+    self.write_updates(root, updates)
+  }
+
+  /// Returns every visible key in order.
+  pub fn keys_in_order(&mut self) -> Result<Vec<String>, Error> {
+    let mut keys = vec![];
+    let root_offset = self.root_offset()?;
+    self.visit_pairs(&root_offset, &mut |pair| {
+      keys.push(pair.key);
+      Ok(())
+    })?;
+    Ok(keys)
+  }
+
+  /// Returns every visible entry in key order.
+  #[allow(clippy::wrong_self_convention)]
+  pub fn to_vec(&mut self) -> Result<Vec<KeyValuePair>, Error> {
+    let mut pairs = vec![];
+    let root_offset = self.root_offset()?;
+    self.visit_pairs(&root_offset, &mut |pair| {
+      pairs.push(pair);
+      Ok(())
+    })?;
+    Ok(pairs)
+  }
+
+  /// Panics if the visible entries are not in strictly increasing key
+  /// order. Cheaper than a full structural check, meant to be called after
+  /// each operation in tests. Does nothing in release builds.
+  pub fn assert_sorted(&mut self) {
+    #[cfg(debug_assertions)]
+    {
+      let pairs = self.to_vec().expect("could not read the tree");
+      if let Some(pair) = pairs.windows(2).find(|pair| pair[0].key >= pair[1].key) {
+        panic!("keys out of order: {:?} is followed by {:?}", pair[0].key, pair[1].key);
+      }
+    }
+  }
+
+  /// Returns every visible entry ordered by value, then by key.
+  pub fn sorted_by_value(&mut self) -> Result<Vec<KeyValuePair>, Error> {
+    self.sorted_by(|a, b| a.value.cmp(&b.value))
+  }
+
+  /// Returns every visible entry ordered by `compare`. The sort is stable,
+  /// so entries that compare equal stay in key order.
+  pub fn sorted_by<F>(&mut self, compare: F) -> Result<Vec<KeyValuePair>, Error>
+  where
+    F: Fn(&KeyValuePair, &KeyValuePair) -> std::cmp::Ordering,
+  {
+    let mut pairs = self.to_vec()?;
+    pairs.sort_by(compare);
+    Ok(pairs)
+  }
+
+  /// Returns every visible entry as a key to value map.
+  #[allow(clippy::wrong_self_convention)]
+  pub fn to_hashmap(&mut self) -> Result<HashMap<String, String>, Error> {
+    Ok(self.to_vec()?.into_iter().map(|kv| (kv.key, kv.value)).collect())
+  }
+
+  /// Rewrites the whole file as a fresh tree with `new_branches`, reinserting
+  /// every visible entry. This is an offline operation: the file is truncated
+  /// before the entries are written back.
+  pub fn rebuild_with_branching_factor(&mut self, new_branches: usize) -> Result<(), Error> {
+    check_branching_factor(new_branches)?;
+
+    let pairs = self.to_vec()?;
+    self.pager.truncate()?;
+    let root = Node::new(NodeType::Leaf(vec![]), true, None);
+    let root_offset = self.pager.write_page(Page::try_from(&root)?)?;
+    self.wal.reset(root_offset)?;
+    self.pending_init = false;
+    self.branches = new_branches;
+
+    for pair in pairs {
+      self.insert(pair)?;
+    }
+    Ok(())
+  }
+
+  /// Walks the tree from the root and, if any page on the way fails to read
+  /// or decode, rebuilds it from the entries of the readable leaves. Entries
+  /// stored under an unreadable page are lost. Pages unreachable from the
+  /// root are only counted: under copy-on-write they hold superseded
+  /// versions of the tree, so reattaching them would bring back overwritten
+  /// and deleted entries.
+  pub fn repair(&mut self) -> Result<RepairReport, Error> {
+    let root_offset = self.root_offset()?;
+    let mut report = RepairReport::default();
+    let mut reachable = HashSet::new();
+    let mut pairs = vec![];
+    self.salvage_sub_tree(root_offset, &mut reachable, &mut pairs, &mut report.unreadable_pages);
+    report.orphaned_pages = self.pager.page_count().saturating_sub(reachable.len());
+    if report.unreadable_pages.is_empty() {
+      return Ok(report);
+    }
+
+    let root = Node::new(NodeType::Leaf(vec![]), true, None);
+    let root_offset = self.pager.write_page(Page::try_from(&root)?)?;
+    self.wal.set_root(root_offset)?;
+    for pair in pairs {
+      self.insert(pair)?;
+      report.reinserted_entries += 1;
+    }
+    Ok(report)
+  }
+
+  /// Writes a `key,value` header and then every entry in key order as CSV,
+  /// quoting fields that need it. Returns the number of entries written.
+  pub fn export_to_csv<W: Write>(&mut self, mut writer: W) -> Result<usize, Error> {
+    writeln!(writer, "key,value")?;
+    let mut written = 0;
+    let root_offset = self.root_offset()?;
+    self.visit_pairs(&root_offset, &mut |pair| {
+      writeln!(writer, "{},{}", csv_field(&pair.key), csv_field(&pair.value))?;
+      written += 1;
+      Ok(())
+    })?;
+    writer.flush()?;
+    Ok(written)
+  }
+
+  /// Upserts every `key,value` record read from `reader`, skipping a leading
+  /// `key,value` header if there is one. Returns the number of records
+  /// imported, or `Error::CsvParseError` with the line of the first bad record.
+  pub fn import_from_csv<R: BufRead>(&mut self, mut reader: R) -> Result<usize, Error> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+
+    let mut imported = 0;
+    for (idx, (line, fields)) in parse_csv(&input)?.into_iter().enumerate() {
+      if idx == 0 && fields == ["key", "value"] {
+        continue;
+      }
+      let [key, value]: [String; 2] = fields.try_into().map_err(|_| Error::CsvParseError(line))?;
+      self.upsert(KeyValuePair::new(key, value))?;
+      imported += 1;
+    }
+    Ok(imported)
+  }
+
+  /// Renders every node with its offset, keys and children, one per line.
+  pub fn format_tree(&mut self) -> Result<String, Error> {
+    let root_offset = self.root_offset()?;
+    let mut out = String::new();
+    self.format_sub_tree(String::from(""), root_offset, &mut out)?;
+    Ok(out)
+  }
+
+  pub fn print(&mut self) -> Result<(), Error> {
+    println!("\n{}", self.format_tree()?);
+    Ok(())
+  }
+
+  /// Renders every leaf in key order, one per line, with its offset, the
+  /// number of pairs it stores (tombstones included) and its first and last
+  /// keys. Leaves carry no sibling pointers, so the order comes from walking
+  /// down from the root rather than following a chain.
+  pub fn print_leaf_chain(&mut self) -> Result<String, Error> {
+    let root_offset = self.root_offset()?;
+    let mut out = String::new();
+    self.visit_nodes(&root_offset, 0, &mut |offset, node, _| {
+      if let NodeType::Leaf(pairs) = &node.node_type {
+        out.push_str(&format!(
+          "Leaf at offset: {}, keys: {}, first: {:?}, last: {:?}\n",
+          offset.0,
+          pairs.len(),
+          pairs.first().map(|kv| kv.key.as_str()),
+          pairs.last().map(|kv| kv.key.as_str()),
+        ));
+      }
+    })?;
+    Ok(out)
+  }
+
+  /// Returns the current root offset, writing the empty root leaf first if
+  /// nothing has been written yet.
+  fn root_offset(&mut self) -> Result<Offset, Error> {
+    if self.pending_init {
+      let root = Node::new(NodeType::Leaf(vec![]), true, None);
+      let root_offset = self.pager.write_page(Page::try_from(&root)?)?;
+      self.wal.set_root(root_offset)?;
+      self.pending_init = false;
+    }
+    self.wal.get_root()
+  }
+
+  /// Fallible part of `Clone`: copies the files and opens the copy.
+  fn try_clone(&self) -> Result<Self, Error> {
+    let path = unique_temp_dir("btree-rs-clone")?.join("db");
+    self.pager.sync()?;
+    self.wal.sync()?;
+    std::fs::copy(&self.path, &path)?;
+    let parent_directory = self.path.parent().unwrap_or_else(|| Path::new("/tmp"));
+    std::fs::copy(parent_directory.join("wal"), path.with_file_name("wal"))?;
+
+    let (pager, wal) = Self::open_storage(&path)?;
+    let mut btree = Self {
+      pending_init: !wal.has_root(),
+      pager,
+      path,
+      branches: self.branches,
+      wal,
+      closed: false,
+      sync_writes: false,
+    };
+    btree.enable_fsync_on_write(self.sync_writes);
+    Ok(btree)
+  }
+
+  /// Opens and locks an existing data file and its WAL, and checks that the
+  /// root page decodes.
+  fn open_storage(path: &Path) -> Result<(Pager, Wal), Error> {
+    let mut pager = Pager::open(path)?;
+    pager.lock_exclusive()?;
+    let parent_directory = path.parent().unwrap_or_else(|| Path::new("/tmp"));
+    let wal = Wal::open(parent_directory.to_path_buf())?;
+    if wal.has_root() {
+      Node::try_from(pager.get_page(&wal.get_root()?)?)?;
+    }
+    Ok((pager, wal))
+  }
+
+  fn shutdown(&mut self) -> Result<(), Error> {
+    if self.closed {
+      return Ok(());
+    }
+    self.closed = true;
+    self.rotate_wal()?;
+    self.pager.unlock()
+  }
+
+  fn insert_non_full(&mut self, node: &mut Node, node_offset: Offset, kv: KeyValuePair) -> Result<(), Error> {
+    let child_idx = node.find_child_index(&kv.key);
+    match &mut node.node_type {
+        NodeType::Leaf(ref mut pairs) => {
+            match pairs.binary_search(&kv) {
+                Ok(idx) if pairs[idx].deleted || pairs[idx].is_expired(unix_timestamp()) => pairs[idx] = kv,
+                Ok(idx) | Err(idx) => pairs.insert(idx, kv),
+            }
+            self.pager.write_page_at_offset(Page::try_from(&*node)?, &node_offset)
+        }
+        NodeType::Internal(ref mut children, ref mut keys) => {
+            let idx = child_idx?;
+            let child_offset = children.get(idx).ok_or(Error::UnexpectedError)?.clone();
+            let child_page = self.pager.get_page(&child_offset)?;
+            let mut child = Node::try_from(child_page)?;
+            let new_child_offset = self.pager.write_page(Page::try_from(&child)?)?;
+            children[idx] = new_child_offset.to_owned();
+            if self.is_node_full(&child)? {
+                let (median, mut sibling) = child.split(Node::optimal_split_idx(self.branches, SplitPolicy::Balanced))?;
+                self.pager.write_page_at_offset(Page::try_from(&child)?, &new_child_offset)?;
+                let sibling_offset = self.pager.write_page(Page::try_from(&sibling)?)?;
+                children.insert(idx + 1, sibling_offset.clone());
+                keys.insert(idx, median.clone());
+                self.pager.write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
+                if kv.key <= median.0 {
+                    self.insert_non_full(&mut child, new_child_offset, kv)
+                } else {
+                    self.insert_non_full(&mut sibling, sibling_offset, kv)
+                }
+            } else {
+                self.pager.write_page_at_offset(Page::try_from(&*node)?, &node_offset)?;
+                self.insert_non_full(&mut child, new_child_offset, kv)
+            }
+        }
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  fn is_node_full(&self, node: &Node) -> Result<bool, Error> {
+    Ok(node.node_type.pair_count()? == (2 * self.branches - 1))
+  }
+
+  fn is_node_underflow(&self, node: &Node) -> Result<bool, Error> {
+    if node.is_root {
+      return Ok(false);
+    }
+    Ok(node.node_type.pair_count()? < self.branches - 1)
+  }
+
+  fn can_lend_key(&self, node: &Node) -> Result<bool, Error> {
+    Ok(node.node_type.pair_count()? > self.branches - 1)
+  }
+
+  /// Smallest visible key above `key` in the subtree at `offset`. Starts at
+  /// the child `key` belongs to and moves right until a key turns up.
+  fn key_after(&mut self, offset: &Offset, key: &str, now: u64) -> Result<Option<String>, Error> {
+    let node = Node::try_from(self.pager.get_page(offset)?)?;
+    let child_idx = node.find_child_index(key);
+    match node.node_type {
+      NodeType::Leaf(pairs) => Ok(pairs
+        .into_iter()
+        .find(|pair| pair.key.as_str() > key && !pair.deleted && !pair.is_expired(now))
+        .map(|pair| pair.key)),
+      NodeType::Internal(children, _) => {
+        for child_offset in children.get(child_idx?..).unwrap_or_default() {
+          if let Some(next) = self.key_after(child_offset, key, now)? {
+            return Ok(Some(next));
+          }
+        }
+        Ok(None)
+      }
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  /// Greatest visible key below `key` in the subtree at `offset`. Starts at
+  /// the child `key` belongs to and moves left until a key turns up.
+  fn key_before(&mut self, offset: &Offset, key: &str, now: u64) -> Result<Option<String>, Error> {
+    let node = Node::try_from(self.pager.get_page(offset)?)?;
+    let child_idx = node.find_child_index(key);
+    match node.node_type {
+      NodeType::Leaf(pairs) => Ok(pairs
+        .into_iter()
+        .rev()
+        .find(|pair| pair.key.as_str() < key && !pair.deleted && !pair.is_expired(now))
+        .map(|pair| pair.key)),
+      NodeType::Internal(children, _) => {
+        for child_offset in children.get(..=child_idx?).unwrap_or_default().iter().rev() {
+          if let Some(previous) = self.key_before(child_offset, key, now)? {
+            return Ok(Some(previous));
+          }
+        }
+        Ok(None)
+      }
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  /// Returns `(tombstones, total)` over every pair stored in the leaves.
+  fn count_pairs(&mut self) -> Result<(usize, usize), Error> {
+    let root_offset = self.root_offset()?;
+    let (mut tombstones, mut total) = (0, 0);
+    self.visit_nodes(&root_offset, 0, &mut |_, node, _| {
+      if let NodeType::Leaf(pairs) = &node.node_type {
+        tombstones += pairs.iter().filter(|pair| pair.deleted).count();
+        total += pairs.len();
+      }
+    })?;
+    Ok((tombstones, total))
+  }
+
+  /// Calls `visit` with the offset, contents and depth of every node of the
+  /// subtree at `offset`, in depth-first pre-order. The subtree's root is at
+  /// `depth`.
+  fn visit_nodes<F>(&mut self, offset: &Offset, depth: usize, visit: &mut F) -> Result<(), Error>
+  where
+    F: FnMut(&Offset, &Node, usize),
+  {
+    let node = Node::try_from(self.pager.get_page(offset)?)?;
+    visit(offset, &node, depth);
+    match node.node_type {
+      NodeType::Internal(children, _) => {
+        for child_offset in children {
+          self.visit_nodes(&child_offset, depth + 1, visit)?;
+        }
+        Ok(())
+      }
+      NodeType::Leaf(_) => Ok(()),
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  /// Calls `visit` with every visible pair of the subtree at `offset`, in key order.
+  fn visit_pairs<F>(&mut self, offset: &Offset, visit: &mut F) -> Result<(), Error>
+  where
+    F: FnMut(KeyValuePair) -> Result<(), Error>,
+  {
+    let node = Node::try_from(self.pager.get_page(offset)?)?;
+    match node.node_type {
+      NodeType::Internal(children, _) => {
+        for child_offset in children {
+          self.visit_pairs(&child_offset, visit)?;
+        }
+        Ok(())
+      }
+      NodeType::Leaf(pairs) => {
+        let now = unix_timestamp();
+        for pair in pairs {
+          if !pair.deleted && !pair.is_expired(now) {
+            visit(pair)?;
+          }
+        }
+        Ok(())
+      }
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  /// Collects the visible pairs of every readable leaf under `offset`, and
+  /// the offsets of the pages that could not be read or decoded.
+  fn salvage_sub_tree(&mut self, offset: Offset, reachable: &mut HashSet<usize>, pairs: &mut Vec<KeyValuePair>, unreadable: &mut Vec<Offset>) {
+    // A page reached twice means a pointer is wrong, so its second parent is
+    // treated as pointing at garbage.
+    if !reachable.insert(offset.0) {
+      unreadable.push(offset);
+      return;
+    }
+    match self.pager.get_page(&offset).and_then(Node::try_from).map(|node| node.node_type) {
+      Ok(NodeType::Internal(children, _)) => {
+        for child_offset in children {
+          self.salvage_sub_tree(child_offset, reachable, pairs, unreadable);
+        }
+      }
+      Ok(NodeType::Leaf(leaf_pairs)) => {
+        let now = unix_timestamp();
+        pairs.extend(leaf_pairs.into_iter().filter(|pair| !pair.deleted && !pair.is_expired(now)));
+      }
+      Ok(NodeType::Unexpected) | Err(_) => unreadable.push(offset),
+    }
+  }
+
+  fn count_prefix_in_sub_tree(&mut self, offset: &Offset, prefix: &str, now: u64) -> Result<usize, Error> {
+    let node = Node::try_from(self.pager.get_page(offset)?)?;
+    match node.node_type {
+      NodeType::Internal(children, keys) => {
+        let mut count = 0;
+        for (idx, child_offset) in children.iter().enumerate() {
+          let lower = idx.checked_sub(1).and_then(|i| keys.get(i));
+          if prefix_in_range(prefix, lower, keys.get(idx)) {
+            count += self.count_prefix_in_sub_tree(child_offset, prefix, now)?;
+          }
+        }
+        Ok(count)
+      }
+      NodeType::Leaf(pairs) => Ok(
+        pairs
+          .iter()
+          .filter(|pair| pair.key.starts_with(prefix) && !pair.deleted && !pair.is_expired(now))
+          .count(),
+      ),
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  /// Removes the first (or last) visible entry from the leftmost (or
+  /// rightmost) leaf, dropping any tombstoned or expired entries before it.
+  fn pop_edge(&mut self, first: bool) -> Result<Option<KeyValuePair>, Error> {
+    let now = unix_timestamp();
+    let visible = |pair: &&KeyValuePair| !pair.deleted && !pair.is_expired(now);
+    loop {
+      let root_offset = self.root_offset()?;
+      let pairs = self.edge_leaf_pairs(&root_offset, first)?;
+      if pairs.is_empty() {
+        return Ok(None);
+      }
+
+      let target = if first {
+        pairs.iter().find(visible).cloned()
+      } else {
+        pairs.iter().rev().find(visible).cloned()
+      };
+      let in_range = |lower: Option<&Key>, upper: Option<&Key>| if first { lower.is_none() } else { upper.is_none() };
+      let (root, _) = self.remove_pairs_in_sub_tree(&root_offset, &in_range, &mut |pair| match &target {
+        Some(target) if first => pair.key <= target.key,
+        Some(target) => pair.key >= target.key,
+        None => true,
+      })?;
+      if let Some(root) = root {
+        self.write_root(root)?;
+      }
+      if target.is_some() {
+        return Ok(target);
+      }
+    }
+  }
+
+  fn edge_leaf_pairs(&mut self, offset: &Offset, first: bool) -> Result<Vec<KeyValuePair>, Error> {
+    let node = Node::try_from(self.pager.get_page(offset)?)?;
+    match node.node_type {
+      NodeType::Internal(children, _) => {
+        let child_offset = if first { children.first() } else { children.last() };
+        self.edge_leaf_pairs(child_offset.ok_or(Error::UnexpectedError)?, first)
+      }
+      NodeType::Leaf(pairs) => Ok(pairs),
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  /// Removes every pair of the leftmost leaf and returns them in order.
+  fn drain_first_leaf(&mut self) -> Result<Vec<KeyValuePair>, Error> {
+    let root_offset = self.root_offset()?;
+    let mut drained = vec![];
+    let (root, _) = self.remove_pairs_in_sub_tree(&root_offset, &|lower, _| lower.is_none(), &mut |pair| {
+      drained.push(pair.clone());
+      true
+    })?;
+    if let Some(root) = root {
+      self.write_root(root)?;
+    }
+    Ok(drained)
+  }
+
+  /// Logs an `Update` for each changed value and publishes `root`. Returns
+  /// the number of updates.
+  fn write_updates(&mut self, root: Node, updates: Vec<(String, String)>) -> Result<usize, Error> {
+    let updated = updates.len();
+    for (key, new_value) in updates {
+      self.wal.append_operation(WalOperation::Update { key, new_value })?;
+    }
+    self.write_root(root)?;
+    Ok(updated)
+  }
+
+  /// Writes `root` to a new page and publishes it, first collapsing any chain
+  /// of internal roots that were left with a single child.
+  fn write_root(&mut self, mut root: Node) -> Result<(), Error> {
+    while let NodeType::Internal(children, keys) = &root.node_type {
+      if !keys.is_empty() {
+        break;
+      }
+      let child_offset = children.first().ok_or(Error::UnexpectedError)?;
+      root = Node::try_from(self.pager.get_page(child_offset)?)?;
+      root.is_root = true;
+      root.parent_offset = None;
+    }
+    let root_offset = self.pager.write_page(Page::try_from(&root)?)?;
+    self.wal.set_root(root_offset)
+  }
+
+  /// Returns the rebalanced node at `offset` and whether it had to change.
+  fn rebalance_sub_tree(&mut self, offset: &Offset) -> Result<(Node, bool), Error> {
+    let mut node = Node::try_from(self.pager.get_page(offset)?)?;
+    let mut changed = false;
+
+    if let NodeType::Internal(ref mut children, _) = node.node_type {
+      for child_offset in children.iter_mut() {
+        let (child, child_changed) = self.rebalance_sub_tree(child_offset)?;
+        if child_changed {
+          *child_offset = self.pager.write_page(Page::try_from(&child)?)?;
+          changed = true;
+        }
+      }
+    }
+
+    changed |= self.fix_children(&mut node)?;
+    Ok((node, changed))
+  }
+
+  /// Removes the pairs selected by `matches` from the subtree at `offset`,
+  /// skipping children whose separator bounds fail `in_range`. Returns the
+  /// rewritten node, if anything was removed, and the number of pairs removed.
+  fn remove_pairs_in_sub_tree<R, M>(&mut self, offset: &Offset, in_range: &R, matches: &mut M) -> Result<(Option<Node>, usize), Error>
+  where
+    R: Fn(Option<&Key>, Option<&Key>) -> bool,
+    M: FnMut(&KeyValuePair) -> bool,
+  {
+    let mut node = Node::try_from(self.pager.get_page(offset)?)?;
+    let mut removed = 0;
+
+    match node.node_type {
+      NodeType::Leaf(ref mut pairs) => {
+        let before = pairs.len();
+        pairs.retain(|pair| !matches(pair));
+        removed = before - pairs.len();
+      }
+      NodeType::Internal(ref mut children, ref keys) => {
+        for (idx, child_offset) in children.iter_mut().enumerate() {
+          let lower = idx.checked_sub(1).and_then(|i| keys.get(i));
+          if !in_range(lower, keys.get(idx)) {
+            continue;
+          }
+          let (child, child_removed) = self.remove_pairs_in_sub_tree(child_offset, in_range, matches)?;
+          if let Some(child) = child {
+            *child_offset = self.pager.write_page(child.repack()?)?;
+            removed += child_removed;
+          }
+        }
+        if removed > 0 {
+          self.fix_children(&mut node)?;
+        }
+      }
+      NodeType::Unexpected => return Err(Error::UnexpectedError),
+    }
+
+    if removed == 0 {
+      return Ok((None, 0));
+    }
+    Ok((Some(node), removed))
+  }
+
+  /// Applies `f` to the visible pairs of the subtree at `offset`, skipping
+  /// children whose separator bounds fail `in_range`, and returns the
+  /// subtree's new root node, or `None` if no value changed. Changed
+  /// children are written to new pages; the returned node is not.
+  fn map_sub_tree<R, F>(&mut self, offset: &Offset, in_range: &R, f: &F, now: u64, updates: &mut Vec<(String, String)>) -> Result<Option<Node>, Error>
+  where
+    R: Fn(Option<&Key>, Option<&Key>) -> bool,
+    F: Fn(&str, String) -> String,
+  {
+    let mut node = Node::try_from(self.pager.get_page(offset)?)?;
+    let mut changed = false;
+    match node.node_type {
+      NodeType::Leaf(ref mut pairs) => {
+        for pair in pairs.iter_mut().filter(|pair| !pair.deleted && !pair.is_expired(now)) {
+          let new_value = f(&pair.key, pair.value.clone());
+          if new_value != pair.value {
+            pair.value = new_value;
+            updates.push((pair.key.clone(), pair.value.clone()));
+            changed = true;
+          }
+        }
+      }
+      NodeType::Internal(ref mut children, ref keys) => {
+        for (idx, child_offset) in children.iter_mut().enumerate() {
+          let lower = idx.checked_sub(1).and_then(|i| keys.get(i));
+          if !in_range(lower, keys.get(idx)) {
+            continue;
+          }
+          if let Some(child) = self.map_sub_tree(child_offset, in_range, f, now, updates)? {
+            *child_offset = self.pager.write_page(Page::try_from(&child)?)?;
+            changed = true;
+          }
+        }
+      }
+      NodeType::Unexpected => return Err(Error::UnexpectedError),
+    }
+    Ok(changed.then_some(node))
+  }
+
+  /// Descends to the leaf that holds `key` and lets `modify` change its pairs
+  /// in place. If it reports a change, the leaf and every node above it are
+  /// copied to new pages and the new node for `offset` is returned.
+  fn modify_leaf_for_key<F>(&mut self, offset: &Offset, key: &str, modify: F) -> Result<Option<Node>, Error>
+  where
+    F: FnOnce(&mut Vec<KeyValuePair>) -> bool,
+  {
+    let mut node = Node::try_from(self.pager.get_page(offset)?)?;
+    let child_idx = node.find_child_index(key);
+    match node.node_type {
+      NodeType::Leaf(ref mut pairs) => {
+        if !modify(pairs) {
+          return Ok(None);
+        }
+      }
+      NodeType::Internal(ref mut children, _) => {
+        let child_offset = children.get_mut(child_idx?).ok_or(Error::UnexpectedError)?;
+        let Some(child) = self.modify_leaf_for_key(child_offset, key, modify)? else {
+          return Ok(None);
+        };
+        *child_offset = self.pager.write_page(Page::try_from(&child)?)?;
+      }
+      NodeType::Unexpected => return Err(Error::UnexpectedError),
+    }
+    Ok(Some(node))
+  }
+
+  /// Returns the subtree at `offset` with its sibling leaves merged, or
+  /// `None` if nothing could be merged, along with the number of merges.
+  fn merge_leaves_in_sub_tree(&mut self, offset: &Offset) -> Result<(Option<Node>, usize), Error> {
+    let mut node = Node::try_from(self.pager.get_page(offset)?)?;
+    let NodeType::Internal(ref mut children, ref mut keys) = node.node_type else {
+      return Ok((None, 0));
+    };
+
+    let mut merges = 0;
+    for child_offset in children.iter_mut() {
+      let (child, child_merges) = self.merge_leaves_in_sub_tree(child_offset)?;
+      if let Some(child) = child {
+        *child_offset = self.pager.write_page(Page::try_from(&child)?)?;
+        merges += child_merges;
+      }
+    }
+
+    let mut idx = 0;
+    while idx + 1 < children.len() {
+      let mut left = Node::try_from(self.pager.get_page(&children[idx])?)?;
+      let right = Node::try_from(self.pager.get_page(&children[idx + 1])?)?;
+      let fits = match (&left.node_type, &right.node_type) {
+        (NodeType::Leaf(left_pairs), NodeType::Leaf(right_pairs)) => left_pairs.len() + right_pairs.len() < 2 * self.branches,
+        _ => false,
+      };
+      if !fits {
+        idx += 1;
+        continue;
+      }
+      left.merge(keys.remove(idx), right)?;
+      children.remove(idx + 1);
+      children[idx] = self.pager.write_page(Page::try_from(&left)?)?;
+      merges += 1;
+    }
+
+    if merges == 0 {
+      return Ok((None, 0));
+    }
+    self.fix_children(&mut node)?;
+    Ok((Some(node), merges))
+  }
+
+  /// Fixes every underflowing child of `node`. Returns whether any moved.
+  fn fix_children(&mut self, node: &mut Node) -> Result<bool, Error> {
+    let mut changed = false;
+    let mut idx = 0;
+    while let NodeType::Internal(children, _) = &node.node_type {
+      if idx >= children.len() {
+        break;
+      }
+      if self.fix_underflow(node, idx)? {
+        changed = true;
+      } else {
+        idx += 1;
+      }
+    }
+    Ok(changed)
+  }
+
+  /// Tops up the child at `idx` from a sibling that can spare a key, or merges
+  /// it with a sibling otherwise. Returns whether the child was underflowing.
+  ///
+  /// A node left with a single child cannot fix that child itself, so the node
+  /// that receives keys or children here gets its own children fixed as well.
+  fn fix_underflow(&mut self, parent: &mut Node, idx: usize) -> Result<bool, Error> {
+    let NodeType::Internal(ref mut children, ref mut keys) = parent.node_type else {
+      return Err(Error::UnexpectedError);
+    };
+    if children.len() < 2 {
+      return Ok(false);
+    }
+
+    let mut child = Node::try_from(self.pager.get_page(&children[idx])?)?;
+    if !self.is_node_underflow(&child)? {
+      return Ok(false);
+    }
+
+    if idx > 0 {
+      let mut left = Node::try_from(self.pager.get_page(&children[idx - 1])?)?;
+      if self.can_lend_key(&left)? {
+        keys[idx - 1] = child.take_last_from(&mut left, keys[idx - 1].clone())?;
+        self.fix_children(&mut child)?;
+        children[idx - 1] = self.pager.write_page(Page::try_from(&left)?)?;
+        children[idx] = self.pager.write_page(Page::try_from(&child)?)?;
+      } else {
+        let separator = keys.remove(idx - 1);
+        children.remove(idx);
+        left.merge(separator, child)?;
+        self.fix_children(&mut left)?;
+        children[idx - 1] = self.pager.write_page(Page::try_from(&left)?)?;
+      }
+      return Ok(true);
+    }
+
+    let mut right = Node::try_from(self.pager.get_page(&children[idx + 1])?)?;
+    if self.can_lend_key(&right)? {
+      keys[idx] = child.take_first_from(&mut right, keys[idx].clone())?;
+      children[idx + 1] = self.pager.write_page(Page::try_from(&right)?)?;
+    } else {
+      let separator = keys.remove(idx);
+      children.remove(idx + 1);
+      child.merge(separator, right)?;
+    }
+    self.fix_children(&mut child)?;
+    children[idx] = self.pager.write_page(Page::try_from(&child)?)?;
+    Ok(true)
+  }
+
+  fn search_node(&mut self, node: Node, search: String) -> Result<Option<KeyValuePair>, Error> {
+    let child_idx = node.find_child_index(&search);
+    match node.node_type {
+        NodeType::Internal(children, _) => {
+            let child_offset = children.get(child_idx?).ok_or(Error::UnexpectedError)?;
+            let page = self.pager.get_page(child_offset)?;
+            let child_node = Node::try_from(page)?;
+            self.search_node(child_node, search)
+        } 
+        NodeType::Leaf(pairs) => {
+            Ok(pairs
+                .binary_search_by_key(&search, |pair| pair.key.clone())
+                .ok()
+                .map(|idx| pairs[idx].clone()))
+        }
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+
+  fn format_sub_tree(&mut self, prefix: String, offset: Offset, out: &mut String) -> Result<(), Error> {
+    out.push_str(&format!("{}Node at offset: {}\n", prefix, offset.0));
+    let curr_prefix = format!("{}|->", prefix);
+    let page = self.pager.get_page(&offset)?;
+    let node = Node::try_from(page)?;
+    match node.node_type {
+        NodeType::Internal(children, keys) => {
+            out.push_str(&format!("{}Keys: {:?}\n", curr_prefix, keys));
+            out.push_str(&format!("{}Children: {:?}\n", curr_prefix, children));
+            let child_prefix = format!("{}   |  ", prefix);
+            for child_offset in children {
+                self.format_sub_tree(child_prefix.clone(), child_offset, out)?;
+            }
+            Ok(())
+        }
+        NodeType::Leaf(pairs) => {
+            out.push_str(&format!("{}Key value pairs: {:?}\n", curr_prefix, pairs));
+            Ok(())
+        }
+        NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
+  }
+}
+
+/// With a branching factor of 1 a node may hold no keys at all, so leaves
+/// can empty out without being merged away.
+fn check_branching_factor(branches: usize) -> Result<(), Error> {
+  if !(2..=MAX_BRANCHING_FACTOR).contains(&branches) {
+    return Err(Error::BranchingFactorOutOfRange { provided: branches, max: MAX_BRANCHING_FACTOR });
+  }
+  Ok(())
+}
+
+/// Whether every node under `offset` lies within the data file and decodes,
+/// with one more child than keys in each internal node.
+fn is_readable_sub_tree(pager: &Pager, offset: &Offset) -> bool {
+  let Ok(node) = pager.read_page(offset).and_then(Node::try_from) else {
+    return false;
+  };
+  match node.node_type {
+    NodeType::Internal(children, keys) => {
+      children.len() == keys.len() + 1 && children.iter().all(|child| is_readable_sub_tree(pager, child))
+    }
+    NodeType::Leaf(_) => true,
+    NodeType::Unexpected => false,
+  }
+}
+
+/// Directory holding the data file at `path`, which is where its WAL lives.
+fn parent_directory(path: &Path) -> Result<PathBuf, Error> {
+  match path.parent() {
+    Some(parent) if parent.as_os_str().is_empty() => Ok(PathBuf::from(".")),
+    Some(parent) => Ok(parent.to_path_buf()),
+    None => Err(Error::UnexpectedError),
+  }
+}
+
+/// Creates a directory under the system temp dir that no other call in any
+/// process has returned.
+fn unique_temp_dir(name: &str) -> Result<PathBuf, Error> {
+  static CREATED: AtomicUsize = AtomicUsize::new(0);
+  let dir = std::env::temp_dir()
+    .join(name)
+    .join(format!("{}-{}", std::process::id(), CREATED.fetch_add(1, Ordering::Relaxed)));
+  std::fs::create_dir_all(&dir)?;
+  Ok(dir)
+}
+
+/// Whether a child holding the keys in `(lower, upper]` can hold a key
+/// starting with `prefix`.
+fn prefix_in_range(prefix: &str, lower: Option<&Key>, upper: Option<&Key>) -> bool {
+  let below = upper.is_some_and(|Key(upper)| upper.as_str() < prefix);
+  let above = lower.is_some_and(|Key(lower)| lower.as_str() > prefix && !lower.starts_with(prefix));
+  !below && !above
+}
+
+fn csv_field(field: &str) -> String {
+  if field.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+/// Splits CSV text into records, each tagged with the line it starts on.
+fn parse_csv(input: &str) -> Result<Vec<(usize, Vec<String>)>, Error> {
+  let mut records = vec![];
+  let mut chars = input.chars().peekable();
+  let mut line = 1;
+
+  while chars.peek().is_some() {
+    let start_line = line;
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    loop {
+      match (quoted, chars.next()) {
+        (true, None) => return Err(Error::CsvParseError(start_line)),
+        (true, Some('"')) if chars.peek() == Some(&'"') => {
+          chars.next();
+          field.push('"');
+        }
+        (true, Some('"')) => {
+          quoted = false;
+          if !matches!(chars.peek(), None | Some(',') | Some('\n') | Some('\r')) {
+            return Err(Error::CsvParseError(line));
+          }
+        }
+        (true, Some(c)) => {
+          if c == '\n' {
+            line += 1;
+          }
+          field.push(c);
+        }
+        (false, Some('"')) if field.is_empty() => quoted = true,
+        (false, Some(',')) => fields.push(std::mem::take(&mut field)),
+        (false, Some('\r')) if chars.peek() == Some(&'\n') => {}
+        (false, Some('\n')) | (false, None) => {
+          line += 1;
+          fields.push(std::mem::take(&mut field));
+          break;
+        }
+        (false, Some(c)) => field.push(c),
+      }
+    }
+    if fields != [""] {
+      records.push((start_line, fields));
+    }
+  }
+  Ok(records)
+}
+
+#[derive(Debug)]
+enum BatchOperation {
+  Insert(KeyValuePair),
+  Delete(String),
+}
+
+/// Inserts and deletes applied together by `BTree::write_batch`.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+  operations: Vec<BatchOperation>,
+}
+
+impl WriteBatch {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn insert(&mut self, kv: KeyValuePair) -> &mut Self {
+    self.operations.push(BatchOperation::Insert(kv));
+    self
+  }
+
+  pub fn delete(&mut self, key: String) -> &mut Self {
+    self.operations.push(BatchOperation::Delete(key));
+    self
+  }
+}
+
+/// Settings for `BTree::new_with_options`. Start from `BTreeOptions::new()`
+/// and override what you need, so new settings can be added without
+/// breaking callers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BTreeOptions {
+  pub branches: usize,
+  /// Must equal `PAGE_SIZE`; pages of other sizes are not supported yet.
+  pub page_size: usize,
+}
+
+impl BTreeOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_branches(mut self, branches: usize) -> Self {
+    self.branches = branches;
+    self
+  }
+
+  pub fn with_page_size(mut self, page_size: usize) -> Self {
+    self.page_size = page_size;
+    self
+  }
+}
+
+impl Default for BTreeOptions {
+  /// 32, or the feature's cap if lower, keeps a full leaf of maximum-size
+  /// keys and values within one page.
+  fn default() -> Self {
+    Self { branches: MAX_BRANCHING_FACTOR.min(32), page_size: PAGE_SIZE }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BTreeFillStats {
+  pub mean: f64,
+  pub min: f64,
+  pub max: f64,
+}
+
+/// What `BTree::repair` found and did.
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+  /// Pages reachable from the root that failed to read or decode.
+  pub unreadable_pages: Vec<Offset>,
+  /// Pages no longer reachable from the root.
+  pub orphaned_pages: usize,
+  /// Entries written back into the rebuilt tree.
+  pub reinserted_entries: usize,
+}
+
+/// A `BTreeRangeIter` with no upper bound, as returned by `BTree::iter` and
+/// `BTree::cursor_from_key`.
+pub type BTreeIter<'a> = BTreeRangeIter<'a>;
+
+pub struct BTreeRangeIter<'a> {
+  btree: &'a BTree,
+  /// Exclusive upper bound, if any.
+  end: Option<String>,
+  /// Children still to visit for each internal node on the way to `leaf`.
+  path: Vec<std::vec::IntoIter<Offset>>,
+  leaf: std::vec::IntoIter<KeyValuePair>,
+  now: u64,
+}
+
+impl BTreeRangeIter<'_> {
+  /// Descends from `offset` to the leaf `start` belongs to, keeping the
+  /// children to the right of the path for later.
+  fn seek(&mut self, mut offset: Offset, start: &str) -> Result<(), Error> {
+    loop {
+      let node = Node::try_from(self.btree.pager.read_page(&offset)?)?;
+      let child_idx = node.find_child_index(start);
+      match node.node_type {
+        NodeType::Internal(children, _) => {
+          let mut children = children.into_iter();
+          offset = children.nth(child_idx?).ok_or(Error::UnexpectedError)?;
+          self.path.push(children);
+        }
+        NodeType::Leaf(mut pairs) => {
+          let from = pairs.partition_point(|kv| kv.key.as_str() < start);
+          self.leaf = pairs.split_off(from).into_iter();
+          return Ok(());
+        }
+        NodeType::Unexpected => return Err(Error::UnexpectedError),
+      }
+    }
+  }
+
+  fn load(&mut self, offset: Offset) -> Result<(), Error> {
+    let node = Node::try_from(self.btree.pager.read_page(&offset)?)?;
+    match node.node_type {
+      NodeType::Internal(children, _) => self.path.push(children.into_iter()),
+      NodeType::Leaf(pairs) => self.leaf = pairs.into_iter(),
+      NodeType::Unexpected => return Err(Error::UnexpectedError),
+    }
+    Ok(())
+  }
+}
+
+impl Iterator for BTreeRangeIter<'_> {
+  type Item = Result<KeyValuePair, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let now = self.now;
+    loop {
+      if let Some(kv) = self.leaf.by_ref().find(|kv| !kv.deleted && !kv.is_expired(now)) {
+        if self.end.as_ref().is_some_and(|end| kv.key >= *end) {
+          self.path.clear();
+          self.leaf = Vec::new().into_iter();
+          return None;
+        }
+        return Some(Ok(kv));
+      }
+      let Some(offset) = self.path.last_mut()?.next() else {
+        self.path.pop();
+        continue;
+      };
+      if let Err(e) = self.load(offset) {
+        self.path.clear();
+        return Some(Err(e));
+      }
+    }
+  }
+}
+
+pub struct BTreeDrain<'a> {
+  btree: &'a mut BTree,
+  buffer: std::vec::IntoIter<KeyValuePair>,
+  done: bool,
+}
+
+impl Iterator for BTreeDrain<'_> {
+  type Item = Result<KeyValuePair, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let now = unix_timestamp();
+    loop {
+      if let Some(kv) = self.buffer.by_ref().find(|kv| !kv.deleted && !kv.is_expired(now)) {
+        return Some(Ok(kv));
+      }
+      if self.done {
+        return None;
+      }
+      match self.btree.drain_first_leaf() {
+        Ok(pairs) if pairs.is_empty() => self.done = true,
+        Ok(pairs) => self.buffer = pairs.into_iter(),
+        Err(e) => {
+          self.done = true;
+          return Some(Err(e));
+        }
+      }
+    }
+  }
+}
+
+impl Drop for BTreeDrain<'_> {
+  fn drop(&mut self) {
+    if !self.done {
+      let _ = self.btree.prefix_delete("");
+    }
+  }
+}
+
+/// Upserts every pair, so a key that is already present takes the new
+/// value. Panics if a write fails, as `Extend` cannot return an error; use
+/// `write_batch` to handle failures.
+impl Extend<KeyValuePair> for BTree {
+  fn extend<I: IntoIterator<Item = KeyValuePair>>(&mut self, iter: I) {
+    for kv in iter {
+      self.upsert(kv).expect("could not write to the tree");
+    }
+  }
+}
+
+/// Collects the pairs into a new tree with the default options, in a fresh
+/// directory under the system temp dir.
+impl FromIterator<KeyValuePair> for Result<BTree, Error> {
+  fn from_iter<I: IntoIterator<Item = KeyValuePair>>(iter: I) -> Self {
+    let dir = unique_temp_dir("btree-rs-collect")?;
+    let mut btree = BTree::new_with_options(dir.join("db"), BTreeOptions::new())?;
+    for kv in iter {
+      btree.upsert(kv)?;
+    }
+    Ok(btree)
+  }
+}
+
+/// Copies the data file and the WAL into a fresh directory under the system
+/// temp dir and opens the copy, so writes to one tree never reach the other.
+/// The copy is byte for byte, tombstones and superseded pages included:
+/// compacting first would need `&mut self`. Panics if the copy fails, as
+/// `Clone` cannot return an error.
+impl Clone for BTree {
+  fn clone(&self) -> Self {
+    self.try_clone().expect("could not copy the tree")
+  }
+}
+
+impl Drop for BTree {
+  fn drop(&mut self) {
+    if self.shutdown().is_err() {
+      let _ = self.pager.unlock();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every test gets its own directory, since the WAL lives next to the data file.
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("btree-rs").join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("db")
+    }
+
+    #[test]
+    fn should_create_new_btree() {
+        let path = temp_path("should_create_new_btree");
+        let branches = 10;
+
+        let btree = BTree::new(&path, branches).unwrap();
+
+        assert_eq!(btree.branches, branches);
+        assert_eq!(btree.path, path);
+    }
+
+    #[test]
+    fn should_create_btree_from_runtime_path() -> Result<(), Error> {
+        let path = temp_path("should_create_btree_from_runtime_path").to_path_buf();
+
+        let mut btree = BTree::with_path(path.clone(), 2)?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("runtime")))?;
+
+        assert_eq!(btree.path, path);
+        assert_eq!(btree.search(String::from("a"))?.unwrap().value, "runtime");
+
+        drop(btree);
+        let mut reopened = BTree::open(&path, 2)?;
+        assert_eq!(reopened.search(String::from("a"))?.unwrap().value, "runtime");
+        Ok(())
+    }
+
+
+    #[test]
+    fn should_create_btree_from_options() -> Result<(), Error> {
+        let path = temp_path("should_create_btree_from_options");
+        let mut btree = BTree::new_with_options(&path, BTreeOptions::new())?;
+        assert_eq!(btree.branches, BTreeOptions::default().branches);
+        for i in 0..500 {
+            btree.insert(KeyValuePair::new(format!("key{:07}", i), "v".repeat(10)))?;
+        }
+        assert_eq!(btree.to_vec()?.len(), 500);
+        drop(btree);
+
+        let btree = BTree::new_with_options(&path, BTreeOptions::new().with_branches(3))?;
+        assert_eq!(btree.branches, 3);
+        drop(btree);
+
+        assert!(matches!(
+            BTree::new_with_options(&path, BTreeOptions::new().with_page_size(8192)),
+            Err(Error::PageSizeMismatch { provided: 8192, expected: PAGE_SIZE })
+        ));
+        assert!(matches!(
+            BTree::new_with_options(&path, BTreeOptions::new().with_branches(0)),
+            Err(Error::BranchingFactorOutOfRange { provided: 0, .. })
+        ));
+        Ok(())
+    }
+
+  #[test]
+    fn should_insert_new_node_with_root_not_full() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_insert_new_node_with_root_not_full"), 2)?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("testing")))?;
+        btree.insert(KeyValuePair::new(String::from("j"), String::from("this")))?;
+        btree.insert(KeyValuePair::new(String::from("i"), String::from("other")))?;
+        btree.insert(KeyValuePair::new(String::from("m"), String::from("insertion")))?;
+        btree.insert(KeyValuePair::new(String::from("b"), String::from("other")))?;
+        btree.insert(KeyValuePair::new(String::from("n"), String::from("lol")))?;
+        btree.insert(KeyValuePair::new(String::from("ab"), String::from("value")))?;
+
+        let _ = btree.print();
+
+        let kv = btree.search(String::from("a"))?;
+        assert_eq!(kv.unwrap(), KeyValuePair::new(String::from("a"), String::from("testing")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_second_writer_on_locked_database() -> Result<(), Error> {
+        let path = temp_path("should_reject_second_writer_on_locked_database");
+        let mut btree = BTree::new(&path, 2)?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("locked")))?;
+
+        // flock locks belong to the open file description, so a second open in
+        // this process conflicts exactly like a second process would.
+        assert!(matches!(BTree::open(&path, 2), Err(Error::DatabaseLocked)));
+        assert!(matches!(BTree::new(&path, 2), Err(Error::DatabaseLocked)));
+
+        drop(btree);
+        let mut reopened = BTree::open(&path, 2)?;
+        let kv = reopened.search(String::from("a"))?.unwrap();
+        assert_eq!(kv.value, "locked");
+
+        Ok(())
+    }
+
+    /// Run by `should_reject_writer_in_another_process` in a child process,
+    /// with the database path and the expected outcome in the environment.
+    /// Does nothing when run directly.
+    #[test]
+    fn open_from_child_process() {
+        let (Ok(path), Ok(expected)) = (std::env::var("BTREE_RS_CHILD_DB"), std::env::var("BTREE_RS_CHILD_EXPECT")) else {
+            return;
+        };
+        match (BTree::open(&path, 2), expected.as_str()) {
+            (Err(Error::DatabaseLocked), "locked") => println!("child: locked"),
+            (Ok(_), "unlocked") => println!("child: unlocked"),
+            (result, expected) => panic!("expected {}, got {:?}", expected, result.map(|_| ())),
+        }
+    }
+
+    fn open_in_child_process(path: &Path, expected: &str) -> std::process::Output {
+        std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "btree::tests::open_from_child_process", "--nocapture", "--test-threads=1"])
+            .env("BTREE_RS_CHILD_DB", path)
+            .env("BTREE_RS_CHILD_EXPECT", expected)
+            .output()
+            .unwrap()
+    }
+
+    #[test]
+    fn should_reject_writer_in_another_process() -> Result<(), Error> {
+        let path = temp_path("should_reject_writer_in_another_process");
+        let mut btree = BTree::new(&path, 2)?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("locked")))?;
+
+        let output = open_in_child_process(&path, "locked");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("child: locked"));
+
+        drop(btree);
+        let output = open_in_child_process(&path, "unlocked");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(String::from_utf8_lossy(&output.stdout).contains("child: unlocked"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_entries_across_wal_rotation() -> Result<(), Error> {
+        let path = temp_path("should_keep_entries_across_wal_rotation");
+        let wal_path = path.parent().unwrap().to_path_buf();
+        let mut btree = BTree::new(&path, 10)?;
+        for i in 0..1000 {
+            btree.insert(KeyValuePair::new(format!("k{:05}", i), format!("v{}", i)))?;
+        }
+
+        btree.rotate_wal()?;
+        let ops = Wal::open(wal_path)?.read_all()?;
+        assert!(matches!(ops[..], [WalOperation::SetRoot(_), WalOperation::Checkpoint]));
+
+        for i in 1000..1500 {
+            btree.insert(KeyValuePair::new(format!("k{:05}", i), format!("v{}", i)))?;
+        }
+        let mut reopened = open_crash_copy(&btree, "should_keep_entries_across_wal_rotation_copy")?;
+        for i in 0..1500 {
+            assert_eq!(reopened.search(format!("k{:05}", i))?.unwrap().value, format!("v{}", i));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_grow_tree_height_on_root_split() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_grow_tree_height_on_root_split"), 2)?;
+        assert_eq!(btree.tree_height()?, 1);
+
+        for key in ["a", "b", "c"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("value")))?;
+        }
+        assert_eq!(btree.tree_height()?, 1);
+
+        btree.insert(KeyValuePair::new(String::from("d"), String::from("value")))?;
+        assert_eq!(btree.tree_height()?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_estimate_height_for_count() -> Result<(), Error> {
+        for b in [1, 2, 10] {
+            assert_eq!(BTree::estimate_height_for_count(0, b), 1);
+            assert_eq!(BTree::estimate_height_for_count(2 * b - 1, b), 1);
+            assert_eq!(BTree::estimate_height_for_count(2 * b, b), 2);
+        }
+        assert_eq!(BTree::estimate_height_for_count(usize::MAX, 2), 33);
+
+        let mut btree = BTree::new(temp_path("should_estimate_height_for_count"), 2)?;
+        for i in 0..200 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", i), String::from("value")))?;
+        }
+        assert!(btree.tree_height()? >= BTree::estimate_height_for_count(200, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn should_suggest_branching_factor_within_height() -> Result<(), Error> {
+        assert_eq!(BTree::suggest_branching_factor(0, 1), 2);
+        assert_eq!(BTree::suggest_branching_factor(usize::MAX, 1), MAX_BRANCHING_FACTOR);
+
+        for (entry_count, max_height) in [(15, 1), (100, 2), (1000, 3)] {
+            let branches = BTree::suggest_branching_factor(entry_count, max_height);
+            let mut btree = BTree::new(temp_path(&format!("should_suggest_branching_factor_within_height_{}", entry_count)), branches)?;
+            for i in 0..entry_count {
+                btree.insert(KeyValuePair::new(format!("k{:04}", (i * 7919) % entry_count), String::from("v")))?;
+            }
+            assert!(btree.tree_height()? <= max_height, "{} entries with b={}", entry_count, branches);
+            assert_eq!(btree.suggest_rebranching(max_height)?, branches);
+        }
+        Ok(())
+    }
+
+    fn assert_no_underflow(btree: &mut BTree, offset: &Offset) -> Result<(), Error> {
+        let node = Node::try_from(btree.pager.get_page(offset)?)?;
+        assert!(!btree.is_node_underflow(&node)?, "underflowing node at offset {}", offset.0);
+        if let NodeType::Internal(children, _) = node.node_type {
+            for child_offset in children {
+                assert_no_underflow(btree, &child_offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebalance_emptied_leaf() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_rebalance_emptied_leaf"), 2)?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+
+        let mut leaf_offset = btree.wal.get_root()?;
+        let mut leaf = Node::try_from(btree.pager.get_page(&leaf_offset)?)?;
+        while let NodeType::Internal(children, _) = &leaf.node_type {
+            leaf_offset = children[0].clone();
+            leaf = Node::try_from(btree.pager.get_page(&leaf_offset)?)?;
+        }
+        let NodeType::Leaf(lost) = leaf.node_type.clone() else {
+            panic!("expected a leaf");
+        };
+        leaf.node_type = NodeType::Leaf(vec![]);
+        btree.pager.write_page_at_offset(Page::try_from(&leaf)?, &leaf_offset)?;
+
+        btree.rebalance()?;
+
+        let root_offset = btree.wal.get_root()?;
+        assert_no_underflow(&mut btree, &root_offset)?;
+        for i in 0..20 {
+            let key = format!("k{:02}", i);
+            if lost.iter().any(|pair| pair.key == key) {
+                assert!(btree.search(key)?.is_none());
+            } else {
+                assert_eq!(btree.search(key)?.unwrap().value, format!("v{}", i));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_delete_keys_with_prefix() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_delete_keys_with_prefix"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("a:{:02}", i), String::from("a")))?;
+            btree.insert(KeyValuePair::new(format!("b:{:02}", i), String::from("b")))?;
+            btree.insert(KeyValuePair::new(format!("c:{:02}", i), String::from("c")))?;
+        }
+
+        assert_eq!(btree.prefix_delete("b:")?, 30);
+        assert_eq!(btree.prefix_delete("b:")?, 0);
+
+        let root_offset = btree.wal.get_root()?;
+        assert_no_underflow(&mut btree, &root_offset)?;
+        for i in 0..30 {
+            assert!(btree.search(format!("a:{:02}", i))?.is_some());
+            assert!(btree.search(format!("b:{:02}", i))?.is_none());
+            assert!(btree.search(format!("c:{:02}", i))?.is_some());
+        }
+
+        assert_eq!(btree.prefix_delete("")?, 60);
+        assert_eq!(btree.tree_height()?, 1);
+        assert!(btree.search(String::from("a:00"))?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_keys_with_prefix() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_count_keys_with_prefix"), 2)?;
+        assert_eq!(btree.keys_with_prefix_count("a")?, 0);
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("a:{:02}", i), String::from("a")))?;
+            btree.insert(KeyValuePair::new(format!("b:{:02}", i), String::from("b")))?;
+            btree.insert(KeyValuePair::new(format!("c:{:02}", i), String::from("c")))?;
+        }
+        btree.soft_delete("b:07")?;
+
+        for prefix in ["", "a", "b:", "b:0", "b:07", "c:2", "c:29", "d", "0"] {
+            let expected = btree.search_by(|kv| kv.key.starts_with(prefix))?.len();
+            assert_eq!(btree.keys_with_prefix_count(prefix)?, expected, "prefix {:?}", prefix);
+        }
+        assert_eq!(btree.keys_with_prefix_count("b:")?, 29);
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_root_when_scanning_pages_by_index() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_find_root_when_scanning_pages_by_index"), 2)?;
+        for key in ["a", "b", "c", "d", "e"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("value")))?;
+        }
+
+        let root_index = btree.wal.get_root()?.0 / PAGE_SIZE;
+        assert!(root_index < btree.pager.page_count());
+
+        let mut root_indexes = vec![];
+        for n in 0..btree.pager.page_count() {
+            if Node::try_from(btree.pager.page_at_index(n)?)?.is_root {
+                root_indexes.push(n);
+            }
+        }
+        assert!(root_indexes.contains(&root_index));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_hide_and_purge_expired_entries() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_hide_and_purge_expired_entries"), 2)?;
+        btree.insert_with_ttl(KeyValuePair::new(String::from("old"), String::from("stale")), 1)?;
+        btree.insert_with_ttl(KeyValuePair::new(String::from("new"), String::from("fresh")), u64::MAX)?;
+        btree.insert(KeyValuePair::new(String::from("forever"), String::from("kept")))?;
+
+        assert!(btree.search(String::from("old"))?.is_none());
+        assert_eq!(btree.search(String::from("new"))?.unwrap().expires_at, Some(u64::MAX));
+        assert_eq!(btree.search(String::from("forever"))?.unwrap().expires_at, None);
+
+        assert_eq!(btree.purge_expired()?, 1);
+        assert_eq!(btree.purge_expired()?, 0);
+        assert_eq!(btree.search(String::from("new"))?.unwrap().value, "fresh");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_replace_expired_entries_on_insert() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_replace_expired_entries_on_insert"), 2)?;
+        btree.insert_with_ttl(KeyValuePair::new(String::from("k"), String::from("old")), 1)?;
+        btree.insert(KeyValuePair::new(String::from("k"), String::from("new")))?;
+
+        let found = btree.search(String::from("k"))?.unwrap();
+        assert_eq!(found.value, "new");
+        assert_eq!(found.expires_at, None);
+        assert!(btree.find_all_duplicates()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_hide_soft_deleted_entries_until_compacted() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_hide_soft_deleted_entries_until_compacted"), 2)?;
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("value")))?;
+        }
+
+        assert!(btree.soft_delete("c")?);
+        assert!(btree.search(String::from("c"))?.is_none());
+        assert!(!btree.soft_delete("c")?);
+        assert!(btree.search(String::from("d"))?.is_some());
+
+        let mut leaf_offset = btree.wal.get_root()?;
+        let mut leaf = Node::try_from(btree.pager.get_page(&leaf_offset)?)?;
+        while let NodeType::Internal(children, _) = &leaf.node_type {
+            leaf_offset = children[leaf.find_child_index("c")?].clone();
+            leaf = Node::try_from(btree.pager.get_page(&leaf_offset)?)?;
+        }
+        let NodeType::Leaf(pairs) = leaf.node_type else {
+            panic!("expected a leaf");
+        };
+        assert!(pairs.iter().any(|pair| pair.key == "c" && pair.deleted));
+
+        assert_eq!(btree.compact()?, 1);
+        assert_eq!(btree.compact()?, 0);
+
+        btree.insert(KeyValuePair::new(String::from("c"), String::from("again")))?;
+        assert_eq!(btree.search(String::from("c"))?.unwrap().value, "again");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_merge_sparse_leaves() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_merge_sparse_leaves"), 3)?;
+        for i in 0..300 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", i), String::from("v")))?;
+        }
+        for i in (0..300).filter(|i| i % 3 != 0) {
+            btree.soft_delete(&format!("k{:03}", i))?;
+        }
+        let height = btree.tree_height()?;
+        let (_, leaves) = btree.node_count()?;
+
+        assert_eq!(btree.compact()?, 200);
+
+        assert!(btree.tree_height()? < height);
+        assert!(btree.node_count()?.1 < leaves);
+        assert_eq!(btree.merge_leaves()?, 0);
+        let root_offset = btree.wal.get_root()?;
+        assert_no_underflow(&mut btree, &root_offset)?;
+        btree.assert_sorted();
+        let keys = btree.keys_in_order()?;
+        assert_eq!(keys, (0..300).step_by(3).map(|i| format!("k{:03}", i)).collect::<Vec<_>>());
+        for key in &keys {
+            assert!(btree.search(key.clone())?.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_drain_every_entry_in_order() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_drain_every_entry_in_order"), 3)?;
+        for i in 0..500 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", (i * 7) % 500), format!("v{}", i)))?;
+        }
+
+        let drained = btree.drain().collect::<Result<Vec<_>, _>>()?;
+        let keys: Vec<String> = drained.into_iter().map(|kv| kv.key).collect();
+        let expected: Vec<String> = (0..500).map(|i| format!("k{:03}", i)).collect();
+        assert_eq!(keys, expected);
+
+        assert_eq!(btree.tree_height()?, 1);
+        assert_eq!(btree.drain().count(), 0);
+        assert!(btree.search(String::from("k000"))?.is_none());
+
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", i), format!("v{}", i)))?;
+        }
+        assert_eq!(btree.drain().next().transpose()?.map(|kv| kv.key), Some(String::from("k000")));
+        assert!(btree.search(String::from("k019"))?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_pop_entries_from_both_ends() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_pop_entries_from_both_ends"), 2)?;
+        assert!(btree.pop_first()?.is_none());
+        assert!(btree.pop_last()?.is_none());
+
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", (i * 37) % 100), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k000")?;
+
+        assert_eq!(btree.pop_last()?.map(|kv| kv.key), Some(String::from("k099")));
+        for i in 1..99 {
+            assert_eq!(btree.pop_first()?.map(|kv| kv.key), Some(format!("k{:03}", i)));
+        }
+        assert!(btree.pop_first()?.is_none());
+        assert_eq!(btree.tree_height()?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_refuse_to_open_foreign_file() -> Result<(), Error> {
+        let path = temp_path("should_refuse_to_open_foreign_file");
+        let mut btree = BTree::new(&path, 2)?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("v")))?;
+        drop(btree);
+
+        let mut seed: u32 = 0x2545_F491;
+        let noise: Vec<u8> = (0..2 * PAGE_SIZE)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect();
+        std::fs::write(&path, noise)?;
+
+        assert!(matches!(BTree::open(&path, 2), Err(Error::InvalidMagic)));
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_data_after_close() -> Result<(), Error> {
+        let path = temp_path("should_keep_data_after_close");
+        let mut btree = BTree::new(&path, 2)?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        btree.close()?;
+
+        let mut reopened = BTree::open(&path, 2)?;
+        for i in 0..20 {
+            assert_eq!(reopened.search(format!("k{:02}", i))?.unwrap().value, format!("v{}", i));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_export_entries_as_csv() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_export_entries_as_csv"), 2)?;
+        btree.insert(KeyValuePair::new(String::from("b"), String::from("a,b")))?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("plain")))?;
+        btree.insert(KeyValuePair::new(String::from("d"), String::from("say \"hi\"")))?;
+        btree.insert(KeyValuePair::new(String::from("c"), String::from("two\nline")))?;
+        btree.insert(KeyValuePair::new(String::from("e"), String::from("gone")))?;
+        btree.soft_delete("e")?;
+
+        let mut out = Vec::new();
+        assert_eq!(btree.export_to_csv(&mut out)?, 4);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "key,value\na,plain\nb,\"a,b\"\nc,\"two\nline\"\nd,\"say \"\"hi\"\"\"\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_import_csv_exported_from_another_tree() -> Result<(), Error> {
+        let mut source = BTree::new(temp_path("should_import_csv_exported_from_another_tree/source"), 2)?;
+        for i in 0..30 {
+            source.insert(KeyValuePair::new(format!("k{:02}", i), format!("v,{}\"", i)))?;
+        }
+        let mut csv = Vec::new();
+        source.export_to_csv(&mut csv)?;
+
+        let mut target = BTree::new(temp_path("should_import_csv_exported_from_another_tree/target"), 3)?;
+        target.insert(KeyValuePair::new(String::from("k00"), String::from("stale")))?;
+        assert_eq!(target.import_from_csv(csv.as_slice())?, 30);
+
+        let mut reexported = Vec::new();
+        target.export_to_csv(&mut reexported)?;
+        assert_eq!(reexported, csv);
+
+        assert_eq!(target.import_from_csv("x,1\ny,2\n".as_bytes())?, 2);
+        assert_eq!(target.search(String::from("y"))?.unwrap().value, "2");
+        assert!(matches!(
+            target.import_from_csv("key,value\na,1\nb,2,3\n".as_bytes()),
+            Err(Error::CsvParseError(3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_sort_entries_by_value() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_sort_entries_by_value"), 2)?;
+        for (key, value) in [("a", "30"), ("b", "10"), ("c", "20"), ("d", "10"), ("e", "05")] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from(value)))?;
+        }
+
+        let by_value: Vec<_> = btree.sorted_by_value()?.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(by_value, vec!["e", "b", "d", "c", "a"]);
+
+        let descending: Vec<_> = btree.sorted_by(|a, b| b.value.cmp(&a.value))?.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(descending, vec!["a", "c", "b", "d", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_assert_sorted_order() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_assert_sorted_order"), 2)?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", (i * 7) % 50), String::from("v")))?;
+            btree_debug_assert_sorted!(btree);
+        }
+
+        // Swapping the root's first two children puts their leaves out of order.
+        let root_offset = btree.root_offset()?;
+        let mut root = Node::try_from(btree.pager.get_page(&root_offset)?)?;
+        if let NodeType::Internal(children, _) = &mut root.node_type {
+            children.swap(0, 1);
+        }
+        btree.pager.write_page_at_offset(Page::try_from(&root)?, &root_offset)?;
+        let unsorted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| btree.assert_sorted()));
+        assert_eq!(unsorted.is_err(), cfg!(debug_assertions));
+        Ok(())
+    }
+
+    #[test]
+    fn should_back_up_while_open() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_back_up_while_open"), 3)?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        let backup_path = temp_path("should_back_up_while_open_copy");
+
+        let copied = btree.backup_to(&backup_path)?;
+        btree.insert(KeyValuePair::new(String::from("after"), String::from("v")))?;
+
+        assert_eq!(copied, std::fs::metadata(&backup_path)?.len() + std::fs::metadata(backup_path.with_file_name("wal"))?.len());
+        let mut backup = BTree::open(&backup_path, 3)?;
+        assert_eq!(backup.to_vec()?.len(), 50);
+        for i in 0..50 {
+            assert_eq!(backup.search(format!("k{:02}", i))?.unwrap().value, format!("v{}", i));
+        }
+        assert!(backup.search(String::from("after"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn should_refuse_to_back_up_over_the_live_files() -> Result<(), Error> {
+        let path = temp_path("should_refuse_to_back_up_over_the_live_files");
+        let mut btree = BTree::new(&path, 3)?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+
+        assert!(matches!(btree.backup_to(&path), Err(Error::WalDirectoryInUse(_))));
+        assert!(matches!(btree.backup_to(path.with_file_name("copy")), Err(Error::WalDirectoryInUse(_))));
+        let dir = path.parent().unwrap();
+        assert!(matches!(btree.wal.backup_to(dir), Err(Error::WalDirectoryInUse(_))));
+        assert!(btree.wal.size_bytes()? > 0);
+
+        let mut reopened = open_crash_copy(&btree, "should_refuse_to_back_up_over_the_live_files_copy")?;
+        assert_eq!(reopened.to_vec()?.len(), 20);
+        Ok(())
+    }
+
+    #[test]
+    fn should_extend_and_collect_pairs() -> Result<(), Error> {
+        let pairs: Vec<_> = (0..60).map(|i| KeyValuePair::new(format!("k{:02}", (i * 7) % 60), format!("v{}", i))).collect();
+
+        let mut btree = BTree::new(temp_path("should_extend_and_collect_pairs"), 3)?;
+        btree.insert(KeyValuePair::new(String::from("k00"), String::from("old")))?;
+        btree.extend(pairs.iter().cloned());
+        assert_eq!(btree.to_vec()?.len(), 60);
+        for kv in &pairs {
+            assert_eq!(btree.search(kv.key.clone())?.unwrap().value, kv.value);
+        }
+
+        let collected: Result<BTree, Error> = pairs.clone().into_iter().collect();
+        let mut collected = collected?;
+        assert_ne!(collected.path, btree.path);
+        let mut expected = pairs;
+        expected.sort();
+        assert_eq!(collected.to_vec()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn should_clone_into_an_independent_tree() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_clone_into_an_independent_tree"), 3)?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k10")?;
+
+        let mut clone = btree.clone();
+        assert_ne!(clone.path, btree.path);
+        assert_eq!(clone.to_vec()?, btree.to_vec()?);
+
+        clone.upsert(KeyValuePair::new(String::from("k20"), String::from("changed")))?;
+        clone.soft_delete("k30")?;
+        clone.insert(KeyValuePair::new(String::from("new"), String::from("v")))?;
+
+        assert_eq!(btree.to_vec()?.len(), 49);
+        assert_eq!(btree.search(String::from("k20"))?.unwrap().value, "v20");
+        assert!(btree.search(String::from("k30"))?.is_some());
+        assert!(btree.search(String::from("new"))?.is_none());
+        assert_eq!(clone.to_vec()?.len(), 49);
+        assert_eq!(clone.search(String::from("k20"))?.unwrap().value, "changed");
+        Ok(())
+    }
+
+    #[test]
+    fn should_round_trip_through_hashmap() -> Result<(), Error> {
+        let map: HashMap<String, String> = (0..100).map(|i| (format!("k{:03}", i), format!("v{}", i))).collect();
+
+        let mut btree = BTree::from_hashmap(map.clone(), temp_path("should_round_trip_through_hashmap"), 3)?;
+
+        assert_eq!(btree.to_vec()?.len(), 100);
+        assert_eq!(btree.search(String::from("k042"))?.unwrap().value, "v42");
+        assert_eq!(btree.to_hashmap()?, map);
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebuild_with_larger_branching_factor() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_rebuild_with_larger_branching_factor"), 2)?;
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", i), format!("v{}", i)))?;
+        }
+        let pairs = btree.to_vec()?;
+        let height = btree.tree_height()?;
+
+        btree.rebuild_with_branching_factor(10)?;
+
+        assert_eq!(btree.branches, 10);
+        assert!(btree.tree_height()? < height);
+        assert_eq!(btree.to_vec()?, pairs);
+        assert!(matches!(btree.rebuild_with_branching_factor(0), Err(Error::BranchingFactorOutOfRange { provided: 0, .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn should_repair_around_an_unreadable_leaf() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_repair_around_an_unreadable_leaf"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        let clean = btree.repair()?;
+        assert!(clean.unreadable_pages.is_empty());
+        assert!(clean.orphaned_pages > 0);
+        assert_eq!(clean.reinserted_entries, 0);
+
+        let leaf_offset = btree.get_page_for_key("k10")?;
+        let lost: Vec<String> = btree.read_leaf_at(leaf_offset.clone())?.into_iter().map(|kv| kv.key).collect();
+        btree.pager.write_page_at_offset(Page::new([0x00; PAGE_SIZE]), &leaf_offset)?;
+        assert!(btree.to_vec().is_err());
+
+        let report = btree.repair()?;
+        assert_eq!(report.unreadable_pages.len(), 1);
+        assert_eq!(report.unreadable_pages[0].0, leaf_offset.0);
+        assert_eq!(report.reinserted_entries, 30 - lost.len());
+        let keys = btree.keys_in_order()?;
+        assert_eq!(keys.len(), 30 - lost.len());
+        assert!(keys.iter().all(|key| !lost.contains(key)));
+        assert!(btree.search(String::from("k10"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn should_iterate_lazily_in_key_order() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_iterate_lazily_in_key_order"), 10)?;
+        for i in 0..2000 {
+            btree.insert(KeyValuePair::new(format!("k{:05}", (i * 7919) % 2000), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k00042")?;
+
+        let mut count = 0;
+        let mut previous: Option<String> = None;
+        for kv in btree.iter()? {
+            let kv = kv?;
+            assert!(previous.is_none_or(|previous| previous < kv.key));
+            previous = Some(kv.key);
+            count += 1;
+        }
+        assert_eq!(count, 1999);
+        assert_eq!(btree.iter()?.collect::<Result<Vec<_>, _>>()?, btree.to_vec()?);
+        let shared = &btree;
+        assert_eq!(shared.iter()?.zip(shared.iter()?.skip(1)).count(), 1998);
+        Ok(())
+    }
+
+    /// The tree this builds is larger than anything the iterator should hold
+    /// at once, but the 100 000 inserts take a while, so run it with
+    /// `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn should_iterate_a_large_tree_lazily() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_iterate_a_large_tree_lazily"), 10)?;
+        for i in 0..100_000 {
+            btree.insert(KeyValuePair::new(format!("k{:05}", (i * 7919) % 100_000), String::from("v")))?;
+        }
+
+        let mut count = 0;
+        let mut previous: Option<String> = None;
+        for kv in btree.iter()? {
+            let kv = kv?;
+            assert!(previous.is_none_or(|previous| previous < kv.key));
+            previous = Some(kv.key);
+            count += 1;
+        }
+        assert_eq!(count, 100_000);
+        Ok(())
+    }
+
+    #[test]
+    fn should_scan_ranges_lazily() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_scan_ranges_lazily"), 2)?;
+        assert_eq!(btree.scan_range_iter("a", "z")?.count(), 0);
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", (i * 37) % 100), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k015")?;
+
+        let keys = |iter: BTreeRangeIter| iter.map(|kv| kv.map(|kv| kv.key)).collect::<Result<Vec<_>, Error>>();
+        let expected: Vec<_> = (10..40).filter(|&i| i != 15).map(|i| format!("k{:03}", i)).collect();
+        assert_eq!(keys(btree.scan_range_iter("k010", "k040")?)?, expected);
+
+        let chained = btree.scan_range_iter("k010", "k020")?.chain(btree.scan_range_iter("k020", "k040")?);
+        assert_eq!(chained.map(|kv| kv.map(|kv| kv.key)).collect::<Result<Vec<_>, Error>>()?, expected);
+
+        assert_eq!(keys(btree.scan_range_iter("k0985", "z")?)?, vec!["k099"]);
+        assert_eq!(keys(btree.scan_range_iter("", "k002")?)?, vec!["k000", "k001"]);
+        assert!(keys(btree.scan_range_iter("k040", "k010")?)?.is_empty());
+        assert!(keys(btree.scan_range_iter("k015", "k016")?)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn should_start_cursor_at_key() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_start_cursor_at_key"), 3)?;
+        assert!(btree.cursor_from_key("k")?.next().is_none());
+
+        for i in 0..1000 {
+            btree.insert(KeyValuePair::new(format!("k{:04}", (i * 7) % 1000), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k0500")?;
+
+        let keys = btree.cursor_from_key("k0500")?.map(|kv| kv.map(|kv| kv.key)).collect::<Result<Vec<_>, Error>>()?;
+        assert_eq!(keys, (501..1000).map(|i| format!("k{:04}", i)).collect::<Vec<_>>());
+
+        let mut cursor = btree.cursor_from_key("k04995")?;
+        assert_eq!(cursor.next().transpose()?.map(|kv| kv.key), Some(String::from("k0501")));
+        assert!(btree.cursor_from_key("k1000")?.next().is_none());
+        assert_eq!(btree.cursor_from_key("")?.count(), 999);
+        Ok(())
+    }
+
+    #[test]
+    fn should_get_nth_entry_in_key_order() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_get_nth_entry_in_key_order"), 3)?;
+        assert!(btree.nth_key(0)?.is_none());
+
+        for i in 0..200 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", (i * 13) % 200), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k000")?;
+        btree.soft_delete("k100")?;
+
+        let pairs = btree.to_vec()?;
+        assert_eq!(btree.nth_key(0)?.map(|kv| kv.key), Some(String::from("k001")));
+        assert_eq!(btree.nth_key(99)?.map(|kv| kv.key), Some(String::from("k101")));
+        assert_eq!(btree.nth_key(pairs.len() - 1)?.map(|kv| kv.key), pairs.last().map(|kv| kv.key.clone()));
+        assert!(btree.nth_key(pairs.len())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_internal_and_leaf_nodes() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_count_internal_and_leaf_nodes"), 2)?;
+        assert_eq!(btree.node_count()?, (0, 1));
+
+        let mut counts = Vec::new();
+        for i in 0..18 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+            counts.push((btree.tree_height()?, btree.node_count()?));
+        }
+        // Each of these inserts is the first one that adds a level.
+        assert_eq!(counts[3], (2, (1, 2)));
+        assert_eq!(counts[8], (3, (3, 4)));
+        assert_eq!(counts[17], (4, (7, 9)));
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_tombstones() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_count_tombstones"), 3)?;
+        assert_eq!(btree.tombstone_count()?, 0);
+        assert_eq!(btree.tombstone_ratio()?, 0.0);
+
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", i), String::from("v")))?;
+        }
+        for i in (0..100).step_by(10) {
+            btree.soft_delete(&format!("k{:03}", i))?;
+        }
+
+        assert_eq!(btree.tombstone_count()?, 10);
+        assert_eq!(btree.tombstone_ratio()?, 0.1);
+        btree.compact()?;
+        assert_eq!(btree.tombstone_count()?, 0);
+        assert_eq!(btree.tombstone_ratio()?, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn should_always_load_a_root_node() -> Result<(), Error> {
+        let path = temp_path("should_always_load_a_root_node");
+        let mut btree = BTree::new(&path, 2)?;
+        assert!(btree.root_node()?.is_root);
+
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+            assert!(btree.root_node()?.is_root);
+        }
+        let root = btree.root_node()?;
+        assert!(!root.is_leaf());
+        let NodeType::Internal(children, _) = root.node_type else {
+            panic!("expected an internal root");
+        };
+        for child_offset in children {
+            assert!(!btree.node_at(child_offset)?.is_root);
+        }
+
+        assert_eq!(btree.prefix_delete("k")?, 30);
+        assert!(btree.root_node()?.is_root);
+        assert!(btree.root_node()?.is_leaf());
+        drop(btree);
+        assert!(BTree::open(&path, 2)?.root_node()?.is_root);
+        Ok(())
+    }
+
+    #[test]
+    fn should_detect_unreachable_pages() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_detect_unreachable_pages"), 2)?;
+        assert!(btree.all_offsets_reachable()?);
+
+        btree.pager.write_page(Page::try_from(&Node::new(NodeType::Leaf(vec![]), true, None))?)?;
+        assert!(!btree.all_offsets_reachable()?);
+
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("v")))?;
+        assert!(!btree.all_offsets_reachable()?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_duplicate_keys() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_find_duplicate_keys"), 2)?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        assert!(btree.find_all_duplicates()?.is_empty());
+
+        let leaf_offset = btree.get_page_for_key("k10")?;
+        let mut leaf = Node::try_from(btree.pager.get_page(&leaf_offset)?)?;
+        let NodeType::Leaf(pairs) = &mut leaf.node_type else {
+            panic!("expected a leaf");
+        };
+        let first = pairs[0].clone();
+        pairs.insert(0, KeyValuePair::new(first.key.clone(), String::from("dup")));
+        pairs.insert(0, KeyValuePair::new(first.key.clone(), String::from("dup2")));
+        btree.pager.write_page_at_offset(Page::try_from(&leaf)?, &leaf_offset)?;
+
+        assert_eq!(btree.find_all_duplicates()?, vec![first.key]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_walk_nodes_with_their_depth() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_walk_nodes_with_their_depth"), 2)?;
+        for i in 0..18 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+
+        let mut per_depth: HashMap<usize, usize> = HashMap::new();
+        let mut leaf_depths = vec![];
+        btree.walk_with_depth(|node, depth| {
+            *per_depth.entry(depth).or_default() += 1;
+            if node.is_leaf() {
+                leaf_depths.push(depth);
+            }
+        })?;
+
+        // Same insert sequence as above: 7 internal nodes over 9 leaves, 4 levels.
+        assert_eq!(per_depth, HashMap::from([(0, 1), (1, 2), (2, 4), (3, 9)]));
+        assert!(leaf_depths.iter().all(|&depth| depth == 3));
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_leaves_at_least_half_full() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_keep_leaves_at_least_half_full"), 10)?;
+        for i in 0..500 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", (i * 37) % 500), String::from("v")))?;
+        }
+
+        let stats = btree.fill_stats()?;
+        assert!(stats.mean >= 0.5);
+        assert!(stats.min >= 9.0 / 19.0);
+        assert!(stats.max <= 1.0);
+        assert_eq!(btree.average_fill_factor()?, stats.mean);
+        Ok(())
+    }
+
+    #[test]
+    fn should_bucket_leaves_by_fill() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_bucket_leaves_by_fill"), 10)?;
+        for i in 0..500 {
+            let kv = KeyValuePair::new(format!("k{:03}", (i * 37) % 500), String::from("v"));
+            if i % 4 == 0 {
+                btree.insert(kv)?;
+            } else {
+                btree.insert_with_ttl(kv, 1)?;
+            }
+        }
+        let before = btree.page_usage_histogram()?;
+        assert_eq!(before.iter().map(|(lower, _)| *lower).collect::<Vec<_>>(), (0..10).map(|n| n * 10).collect::<Vec<_>>());
+        assert_eq!(before.iter().map(|(_, count)| count).sum::<usize>(), btree.node_count()?.1);
+        assert!(before[9].1 > 0);
+
+        btree.purge_expired()?;
+        let after = btree.page_usage_histogram()?;
+        let lower: usize = after[..6].iter().map(|(_, count)| count).sum();
+        let upper: usize = after[6..].iter().map(|(_, count)| count).sum();
+        assert!(lower > upper);
+        assert_eq!(after[9].1, 0);
+        // Underflowing leaves are merged or refilled, so none drops below
+        // `branches - 1` pairs.
+        assert!(after[..4].iter().all(|(_, count)| *count == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_longest_key_and_value() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_find_longest_key_and_value"), 2)?;
+        assert_eq!(btree.max_key_length_bytes()?, 0);
+        assert_eq!(btree.max_value_length_bytes()?, 0);
+
+        for i in 1..8 {
+            btree.insert(KeyValuePair::new("k".repeat(i), "v".repeat(8 - i)))?;
+        }
+        btree.insert(KeyValuePair::new(String::from("ключ"), String::from("зн")))?;
+        assert_eq!(btree.max_key_length_bytes()?, 8);
+        assert_eq!(btree.max_value_length_bytes()?, 7);
+
+        btree.soft_delete("ключ")?;
+        btree.soft_delete("k")?;
+        assert_eq!(btree.max_key_length_bytes()?, 7);
+        assert_eq!(btree.max_value_length_bytes()?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn should_expose_pager_and_wal_read_only() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_expose_pager_and_wal_read_only"), 2)?;
+        assert_eq!(btree.pager().page_count(), 0);
+        assert!(!btree.wal().has_root());
+
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        let root_offset = btree.root_offset()?;
+        assert_eq!(btree.wal().get_root()?.0, root_offset.0);
+        assert_eq!(btree.wal().entry_count()?, 41);
+        assert_eq!(btree.pager().page_count() * PAGE_SIZE, std::fs::metadata(&btree.path)?.len() as usize);
+        assert!(Node::try_from(btree.pager().read_page(&root_offset)?)?.is_root);
+        Ok(())
+    }
+
+    #[test]
+    fn should_leave_minimal_wal_after_checkpoint() -> Result<(), Error> {
+        let path = temp_path("should_leave_minimal_wal_after_checkpoint");
+        let mut btree = BTree::new(&path, 2)?;
+        btree.flush_and_checkpoint()?;
+        assert_eq!(btree.wal.size_bytes()?, 0);
+
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        let root_offset = btree.wal.get_root()?;
+        let before = btree.wal.size_bytes()?;
+
+        btree.flush_and_checkpoint()?;
+
+        assert!(btree.wal.size_bytes()? < before);
+        let ops = btree.wal.read_all()?;
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(&ops[0], WalOperation::SetRoot(offset) if offset.0 == root_offset.0));
+        assert!(matches!(ops[1], WalOperation::Checkpoint));
+        assert_eq!(open_crash_copy(&btree, "should_leave_minimal_wal_after_checkpoint_copy")?.to_vec()?.len(), 50);
+        Ok(())
+    }
+
+    /// Copies the data file and the WAL as they are now, which is what a
+    /// crash at this point would leave behind, and returns the copy's path.
+    fn crash_copy(btree: &BTree, name: &str) -> Result<PathBuf, Error> {
+        let copy_path = temp_path(name);
+        std::fs::copy(&btree.path, &copy_path)?;
+        std::fs::copy(btree.path.with_file_name("wal"), copy_path.with_file_name("wal"))?;
+        Ok(copy_path)
+    }
+
+    /// Opens a `crash_copy` of `btree`.
+    fn open_crash_copy(btree: &BTree, name: &str) -> Result<BTree, Error> {
+        BTree::open(crash_copy(btree, name)?, btree.branches)
+    }
+
+    #[test]
+    fn should_sync_every_write_when_enabled() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_sync_every_write_when_enabled"), 2)?;
+        assert!(!btree.pager().sync_writes() && !btree.wal().sync_writes());
+
+        btree.enable_fsync_on_write(true);
+        assert!(btree.pager().sync_writes() && btree.wal().sync_writes());
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("v")))?;
+
+        let reloaded_path = temp_path("should_sync_every_write_when_enabled_reloaded");
+        BTree::new(&reloaded_path, 2)?.close()?;
+        btree.hot_reload(&reloaded_path)?;
+        assert!(btree.pager().sync_writes() && btree.wal().sync_writes());
+
+        btree.enable_fsync_on_write(false);
+        assert!(!btree.pager().sync_writes() && !btree.wal().sync_writes());
+        Ok(())
+    }
+
+    #[test]
+    fn should_format_tree_without_printing() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_format_tree_without_printing"), 2)?;
+        for key in ["a", "b", "c", "d"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("v")))?;
+        }
+
+        let root_offset = btree.wal.get_root()?;
+        let tree = btree.format_tree()?;
+        assert!(tree.starts_with(&format!("Node at offset: {}\n", root_offset.0)));
+        assert!(tree.contains("Keys: [Key(\"b\")]"));
+        assert_eq!(tree.matches("Node at offset").count(), 3);
+        assert!(tree.contains("key: \"d\""));
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_leaves_in_key_order() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_list_leaves_in_key_order"), 2)?;
+        assert_eq!(btree.print_leaf_chain()?, format!("Leaf at offset: {}, keys: 0, first: None, last: None\n", btree.wal.get_root()?.0));
+
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", (i * 7) % 30), String::from("v")))?;
+        }
+        let chain = btree.print_leaf_chain()?;
+        let lines = chain.lines().collect::<Vec<_>>();
+
+        let mut leaf_offsets = vec![];
+        let root_offset = btree.wal.get_root()?;
+        btree.visit_nodes(&root_offset, 0, &mut |offset, node, _| {
+            if matches!(node.node_type, NodeType::Leaf(_)) {
+                leaf_offsets.push(offset.0);
+            }
+        })?;
+        assert!(leaf_offsets.len() > 1);
+        assert_eq!(lines.len(), leaf_offsets.len());
+        for (line, offset) in lines.iter().zip(&leaf_offsets) {
+            assert!(line.starts_with(&format!("Leaf at offset: {},", offset)));
+        }
+        assert!(lines[0].contains("first: Some(\"k00\")"));
+        assert!(lines[lines.len() - 1].ends_with("last: Some(\"k29\")"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_search_by_value_predicate() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_search_by_value_predicate"), 3)?;
+        for i in 0..60 {
+            let value = if i % 7 == 0 { format!("red-{}", i) } else { format!("blue-{}", i) };
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), value))?;
+        }
+        btree.soft_delete("k14")?;
+
+        let found = btree.search_by(|kv| kv.value.starts_with("red"))?;
+        let keys: Vec<_> = found.iter().map(|kv| kv.key.as_str()).collect();
+        assert_eq!(keys, vec!["k00", "k07", "k21", "k28", "k35", "k42", "k49", "k56"]);
+        assert!(btree.search_by(|kv| kv.value.is_empty())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_by_predicate() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_count_by_predicate"), 3)?;
+        for i in 0..60 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("{}", i % 4)))?;
+        }
+        btree.soft_delete("k00")?;
+
+        assert_eq!(btree.count_by(|_| true)?, btree.to_vec()?.len());
+        let is_zero = |kv: &KeyValuePair| kv.value == "0";
+        assert_eq!(btree.count_by(is_zero)?, btree.search_by(is_zero)?.len());
+        assert_eq!(btree.count_by(is_zero)?, 14);
+        Ok(())
+    }
+
+    #[test]
+    fn should_group_by_prefix() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_group_by_prefix"), 2)?;
+        for key in ["user:1:a", "user:2:b", "user:10", "post:1", "post:22", "plain", "order:7"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("v")))?;
+        }
+
+        let groups = btree.group_by_prefix(':')?;
+        let keys_of = |prefix: &str| groups[prefix].iter().map(|kv| kv.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(groups.len(), 4);
+        assert_eq!(keys_of("user"), vec!["user:10", "user:1:a", "user:2:b"]);
+        assert_eq!(keys_of("post"), vec!["post:1", "post:22"]);
+        assert_eq!(keys_of("order"), vec!["order:7"]);
+        assert_eq!(keys_of(""), vec!["plain"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_bound_branching_factor_by_max() -> Result<(), Error> {
+        let path = temp_path("should_bound_branching_factor_by_max");
+        for branches in [2, MAX_BRANCHING_FACTOR] {
+            BTree::new(&path, branches)?;
+        }
+        for branches in [0, 1, MAX_BRANCHING_FACTOR + 1] {
+            let err = BTree::new(&path, branches).unwrap_err();
+            assert!(matches!(err, Error::BranchingFactorOutOfRange { provided, max } if provided == branches && max == MAX_BRANCHING_FACTOR));
+            assert_eq!(err.to_string(), format!("branching factor {} is out of range 2..={}", branches, MAX_BRANCHING_FACTOR));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_get_through_shared_reference() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_get_through_shared_reference"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k07")?;
+
+        let shared = &btree;
+        for i in 0..30 {
+            let key = format!("k{:02}", i);
+            let found = shared.get(&key)?.map(|kv| kv.value);
+            assert_eq!(found, if i == 7 { None } else { Some(format!("v{}", i)) });
+        }
+        assert!(shared.get("missing")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn should_fall_back_to_default_for_missing_keys() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_fall_back_to_default_for_missing_keys"), 2)?;
+        assert_eq!(btree.get_or_default("a", "fallback")?, "fallback");
+
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("one")))?;
+        btree.insert(KeyValuePair::new(String::from("b"), String::from("two")))?;
+        btree.soft_delete("b")?;
+
+        assert_eq!(btree.get_or_default("a", "fallback")?, "one");
+        assert_eq!(btree.get_or_default("b", "fallback")?, "fallback");
+        assert_eq!(btree.get_or_default("c", "fallback")?, "fallback");
+        Ok(())
+    }
+
+    #[test]
+    fn should_return_replaced_entry() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_return_replaced_entry"), 2)?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k05")?;
+
+        let old = btree.replace(KeyValuePair::new(String::from("k07"), String::from("new")))?;
+        assert_eq!(old.map(|kv| kv.value), Some(String::from("v7")));
+        assert_eq!(btree.search(String::from("k07"))?.map(|kv| kv.value), Some(String::from("new")));
+
+        assert!(btree.replace(KeyValuePair::new(String::from("k05"), String::from("back")))?.is_none());
+        assert!(btree.replace(KeyValuePair::new(String::from("k20"), String::from("fresh")))?.is_none());
+        assert_eq!(btree.search(String::from("k05"))?.map(|kv| kv.value), Some(String::from("back")));
+        assert_eq!(btree.keys_in_order()?.len(), 21);
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_that_all_keys_are_present() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_check_that_all_keys_are_present"), 2)?;
+        assert!(!btree.contains_all(&["a"])?);
+        assert!(btree.contains_all(&[])?);
+
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        btree.soft_delete("k13")?;
+
+        assert!(btree.contains_all(&["k00", "k07", "k19"])?);
+        assert!(!btree.contains_all(&["k00", "missing", "k19"])?);
+        assert!(!btree.contains_all(&["k13"])?);
+        assert!(btree.contains_all(&[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_check_that_any_key_is_present() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_check_that_any_key_is_present"), 2)?;
+        assert!(!btree.contains_any(&["a"])?);
+
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        btree.soft_delete("k13")?;
+
+        assert!(btree.contains_any(&["missing", "k07"])?);
+        assert!(btree.contains_any(&["k00", "k07", "k19"])?);
+        assert!(!btree.contains_any(&["missing", "k13", "k20"])?);
+        assert!(!btree.contains_any(&[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_lowest_common_ancestor() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_find_lowest_common_ancestor"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+
+        let root_offset = btree.wal.get_root()?;
+        assert_eq!(btree.lowest_common_ancestor("k00", "k29")?.0, root_offset.0);
+
+        let offset = btree.lowest_common_ancestor("k00", "k01")?;
+        let NodeType::Leaf(pairs) = Node::try_from(btree.pager.get_page(&offset)?)?.node_type else {
+            panic!("expected a leaf at offset {}", offset.0);
+        };
+        assert!(pairs.iter().any(|pair| pair.key == "k00") && pairs.iter().any(|pair| pair.key == "k01"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_pages_at_raw_offsets() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_read_pages_at_raw_offsets"), 2)?;
+        for key in ["a", "b", "c", "d"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("v")))?;
+        }
+        btree.soft_delete("a")?;
+
+        let root_offset = btree.wal.get_root()?;
+        assert_eq!(btree.read_internal_keys_at(root_offset.clone())?, vec![Key(String::from("b"))]);
+        assert!(btree.read_leaf_at(root_offset.clone()).is_err());
+
+        let NodeType::Internal(children, _) = Node::try_from(btree.pager.get_page(&root_offset)?)?.node_type else {
+            panic!("expected an internal root");
+        };
+        let left = btree.read_leaf_at(children[0].clone())?;
+        assert_eq!(left.iter().map(|pair| (pair.key.as_str(), pair.deleted)).collect::<Vec<_>>(), vec![("a", true), ("b", false)]);
+        assert!(btree.read_internal_keys_at(children[1].clone()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_count_wal_entries_per_insert() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_count_wal_entries_per_insert"), 2)?;
+        assert_eq!(btree.wal.entry_count()?, 0);
+
+        for i in 0..10 {
+            btree.insert(KeyValuePair::new(format!("k{}", i), String::from("v")))?;
+        }
+        // The first insert also logs the empty root it starts from; each
+        // insert then logs the pair and the new root.
+        assert_eq!(btree.wal.entry_count()?, 21);
+        assert_eq!(btree.wal.entry_count()?, btree.wal.read_all()?.len());
+
+        let size = btree.wal.size_bytes()?;
+        btree.soft_delete("k3")?;
+        assert_eq!(btree.wal.entry_count()?, 23);
+        assert!(btree.wal.size_bytes()? > size);
+        Ok(())
+    }
+
+    #[test]
+    fn should_hot_reload_another_data_file() -> Result<(), Error> {
+        let old_path = temp_path("should_hot_reload_another_data_file_old");
+        let new_path = temp_path("should_hot_reload_another_data_file_new");
+
+        let mut replacement = BTree::new(&new_path, 2)?;
+        for i in 0..20 {
+            replacement.insert(KeyValuePair::new(format!("new{:02}", i), format!("v{}", i)))?;
+        }
+        replacement.close()?;
+
+        let mut btree = BTree::new(&old_path, 2)?;
+        btree.insert(KeyValuePair::new(String::from("old"), String::from("v")))?;
+        assert!(btree.hot_reload("/nonexistent/db").is_err());
+        let sibling = old_path.with_file_name("sibling");
+        assert!(matches!(btree.hot_reload(&sibling), Err(Error::WalDirectoryInUse(_))));
+        assert!(matches!(btree.hot_reload(&old_path), Err(Error::WalDirectoryInUse(_))));
+        assert!(btree.search(String::from("old"))?.is_some());
+
+        btree.hot_reload(&new_path)?;
+        assert!(btree.search(String::from("old"))?.is_none());
+        assert_eq!(btree.to_vec()?.len(), 20);
+        assert_eq!(btree.search(String::from("new07"))?.unwrap().value, "v7");
+        assert!(matches!(BTree::open(&new_path, 2), Err(Error::DatabaseLocked)));
+
+        let mut old = BTree::open(&old_path, 2)?;
+        assert!(old.search(String::from("old"))?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn should_apply_write_batch_atomically() -> Result<(), Error> {
+        let path = temp_path("should_apply_write_batch_atomically");
+        let mut btree = BTree::new(&path, 2)?;
+        for key in ["a", "b", "c"] {
+            btree.insert(KeyValuePair::new(String::from(key), String::from("v")))?;
+        }
+
+        let mut batch = WriteBatch::new();
+        batch
+            .insert(KeyValuePair::new(String::from("d"), String::from("v")))
+            .delete(String::from("a"))
+            .insert(KeyValuePair::new(String::from("key too long"), String::from("v")));
+        assert!(matches!(btree.write_batch(batch), Err(Error::KeyOverflowError)));
+        let keys: Vec<_> = btree.to_vec()?.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+
+        let mut batch = WriteBatch::new();
+        batch.insert(KeyValuePair::new(String::from("d"), String::from("v"))).delete(String::from("a"));
+        btree.write_batch(batch)?;
+
+        let mut reopened = open_crash_copy(&btree, "should_apply_write_batch_atomically_copy")?;
+        let keys: Vec<_> = reopened.to_vec()?.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, vec!["b", "c", "d"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_writes_made_after_an_unfinished_batch() -> Result<(), Error> {
+        let path = temp_path("should_keep_writes_made_after_an_unfinished_batch");
+        let mut btree = BTree::new(&path, 2)?;
+        btree.insert(KeyValuePair::new(String::from("a"), String::from("v")))?;
+        btree.wal.append_operation(WalOperation::Begin)?;
+        let after_begin = btree.wal.size_bytes()?;
+        btree.insert(KeyValuePair::new(String::from("pending"), String::from("v")))?;
+
+        let crashed = crash_copy(&btree, "should_keep_writes_made_after_an_unfinished_batch_1")?;
+        std::fs::OpenOptions::new().write(true).open(crashed.with_file_name("wal"))?.set_len(after_begin)?;
+        drop(btree);
+
+        let mut reopened = BTree::open(&crashed, 2)?;
+        assert!(reopened.search(String::from("pending"))?.is_none());
+        reopened.insert(KeyValuePair::new(String::from("b"), String::from("v")))?;
+        let crashed_again = crash_copy(&reopened, "should_keep_writes_made_after_an_unfinished_batch_2")?;
+        drop(reopened);
+
+        let mut reopened = BTree::open(&crashed_again, 2)?;
+        let keys: Vec<_> = reopened.to_vec()?.into_iter().map(|kv| kv.key).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn should_visit_every_entry_with_for_each() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_visit_every_entry_with_for_each"), 3)?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), "x".repeat(i % 10)))?;
+        }
+
+        let mut total = 0;
+        btree.for_each(|kv| {
+            total += kv.value.len();
+            Ok(())
+        })?;
+        assert_eq!(total, btree.to_vec()?.iter().map(|kv| kv.value.len()).sum::<usize>());
+
+        let mut visited = 0;
+        let stopped = btree.for_each(|kv| {
+            visited += 1;
+            if kv.key == "k09" { Err(Error::UnexpectedError) } else { Ok(()) }
+        });
+        assert!(matches!(stopped, Err(Error::UnexpectedError)));
+        assert_eq!(visited, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn should_map_values_in_place() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_map_values_in_place"), 2)?;
+        for i in 0..40 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k05")?;
+
+        let before = btree.to_vec()?;
+        assert_eq!(btree.map_values(|_, value| value + "_v2")?, 39);
+        let after = btree.to_vec()?;
+        assert_eq!(after.len(), before.len());
+        for (old, new) in before.iter().zip(&after) {
+            assert_eq!((&new.key, &new.value), (&old.key, &format!("{}_v2", old.value)));
+        }
+        assert!(btree.search(String::from("k05"))?.is_none());
+
+        let pages = btree.pager.page_count();
+        assert_eq!(btree.map_values(|key, value| if key == "k10" { String::from("ten") } else { value })?, 1);
+        assert_eq!(btree.search(String::from("k10"))?.unwrap().value, "ten");
+        assert_eq!(btree.pager.page_count() - pages, btree.tree_height()?);
+        assert_eq!(btree.map_values(|_, value| value)?, 0);
+        assert_eq!(btree.pager.page_count() - pages, btree.tree_height()?);
+        Ok(())
+    }
+
+    #[test]
+    fn should_update_values_in_key_range() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_update_values_in_key_range"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("a:{:02}", i), String::from("1")))?;
+            btree.insert(KeyValuePair::new(format!("b:{:02}", i), String::from("1")))?;
+            btree.insert(KeyValuePair::new(format!("c:{:02}", i), String::from("1")))?;
+        }
+        btree.soft_delete("b:03")?;
+
+        let increment = |_: &str, value: String| (value.parse::<u32>().unwrap() + 1).to_string();
+        assert_eq!(btree.update_range("b:", "c:", increment)?, 29);
+        assert_eq!(btree.update_range("b:10", "b:20", increment)?, 10);
+        for kv in btree.to_vec()? {
+            let expected = match kv.key.as_str() {
+                key if key.starts_with("b:1") => "3",
+                key if key.starts_with("b:") => "2",
+                _ => "1",
+            };
+            assert_eq!(kv.value, expected, "key {}", kv.key);
+        }
+        assert!(btree.search(String::from("b:03"))?.is_none());
+        assert_eq!(btree.update_range("z", "zz", increment)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn should_defer_root_write_until_first_insert() -> Result<(), Error> {
+        let path = temp_path("should_defer_root_write_until_first_insert");
+        let mut btree = BTree::new(&path, 2)?;
+        assert!(btree.search(String::from("a"))?.is_none());
+        assert!(btree.get("a")?.is_none());
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        assert_eq!(btree.wal.size_bytes()?, 0);
+        btree.close()?;
+
+        let mut reopened = BTree::open(&path, 2)?;
+        assert!(reopened.search(String::from("a"))?.is_none());
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+        reopened.insert(KeyValuePair::new(String::from("a"), String::from("v")))?;
+        assert_eq!(reopened.search(String::from("a"))?.unwrap().value, "v");
+        assert_eq!(reopened.pager.page_count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_first_key_after() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_find_first_key_after"), 2)?;
+        assert_eq!(btree.first_key_after("a")?, None);
+        for i in (0..40).step_by(2) {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        btree.soft_delete("k10")?;
+
+        assert_eq!(btree.first_key_after("k04")?.as_deref(), Some("k06"));
+        assert_eq!(btree.first_key_after("k05")?.as_deref(), Some("k06"));
+        assert_eq!(btree.first_key_after("k08")?.as_deref(), Some("k12"));
+        assert_eq!(btree.first_key_after("a")?.as_deref(), Some("k00"));
+        assert_eq!(btree.first_key_after("k38")?, None);
+        assert_eq!(btree.first_key_after("z")?, None);
+        for i in (0..38).step_by(2).filter(|&i| i != 8 && i != 10) {
+            assert_eq!(btree.first_key_after(&format!("k{:02}", i))?, Some(format!("k{:02}", i + 2)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_find_last_key_before() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_find_last_key_before"), 2)?;
+        for i in (0..40).step_by(2) {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        btree.soft_delete("k10")?;
+
+        assert_eq!(btree.last_key_before("k06")?.as_deref(), Some("k04"));
+        assert_eq!(btree.last_key_before("k05")?.as_deref(), Some("k04"));
+        assert_eq!(btree.last_key_before("k12")?.as_deref(), Some("k08"));
+        assert_eq!(btree.last_key_before("z")?.as_deref(), Some("k38"));
+        assert_eq!(btree.last_key_before("k00")?, None);
+        assert_eq!(btree.last_key_before("a")?, None);
+        for i in (2..40).step_by(2).filter(|&i| i != 10 && i != 12) {
+            assert_eq!(btree.last_key_before(&format!("k{:02}", i))?, Some(format!("k{:02}", i - 2)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebuild_index_after_wal_loss() -> Result<(), Error> {
+        let path = temp_path("should_rebuild_index_after_wal_loss");
+        let mut btree = BTree::new(&path, 3)?;
+        for i in 0..100 {
+            btree.insert(KeyValuePair::new(format!("k{:03}", i), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k050")?;
+        drop(btree);
+        std::fs::remove_file(path.parent().unwrap().join("wal"))?;
+        assert!(BTree::open(&path, 3).is_err());
+
+        let mut rebuilt = BTree::rebuild_index(&path, 3)?;
+        assert_eq!(rebuilt.to_vec()?.len(), 99);
+        assert_eq!(rebuilt.search(String::from("k099"))?.unwrap().value, "v99");
+        assert!(rebuilt.search(String::from("k050"))?.is_none());
+        rebuilt.insert(KeyValuePair::new(String::from("k100"), String::from("v100")))?;
+        drop(rebuilt);
+
+        let mut reopened = BTree::open(&path, 3)?;
+        assert_eq!(reopened.to_vec()?.len(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn should_rebuild_index_from_the_last_readable_root() -> Result<(), Error> {
+        let path = temp_path("should_rebuild_index_from_the_last_readable_root");
+        let mut btree = BTree::new(&path, 3)?;
+        for i in 0..20 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+        let mut batch = WriteBatch::new();
+        batch
+            .insert(KeyValuePair::new(String::from("zz"), String::from("v")))
+            .insert(KeyValuePair::new(String::from("key too long"), String::from("v")));
+        assert!(btree.write_batch(batch).is_err());
+        assert!(btree.search(String::from("zz"))?.is_none());
+        drop(btree);
+        std::fs::remove_file(path.parent().unwrap().join("wal"))?;
+
+        let mut pager = Pager::open(&path)?;
+        let dangling = Node::new(NodeType::Internal(vec![Offset(1 << 40)], vec![]), true, None);
+        pager.write_page(Page::try_from(&dangling)?)?;
+        drop(pager);
+
+        // The pages of the rolled back batch hold a complete tree, so its
+        // insert of `zz` comes back; the dangling root after it is skipped.
+        let mut rebuilt = BTree::rebuild_index(&path, 3)?;
+        let keys = rebuilt.keys_in_order()?;
+        assert_eq!(keys.len(), 21);
+        assert!((0..20).all(|i| keys.contains(&format!("k{:02}", i))));
+        assert_eq!(rebuilt.search(String::from("zz"))?.unwrap().value, "v");
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_keys_in_order() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_list_keys_in_order"), 3)?;
+        for i in 0..50 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", (i * 17) % 50), format!("v{}", i)))?;
+        }
+        btree.soft_delete("k25")?;
+
+        let keys = btree.keys_in_order()?;
+        assert_eq!(keys.len(), 49);
+        assert_eq!(keys, btree.to_vec()?.into_iter().map(|kv| kv.key).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn should_get_leaf_page_for_key() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_get_leaf_page_for_key"), 2)?;
+        let empty_root = btree.get_page_for_key("a")?;
+        assert_eq!(empty_root.0, btree.wal.get_root()?.0);
+
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+        for i in 0..30 {
+            let key = format!("k{:02}", i);
+            let offset = btree.get_page_for_key(&key)?;
+            let pairs = btree.read_leaf_at(offset)?;
+            assert!(pairs.iter().any(|pair| pair.key == key));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_scan_leaves_between_page_offsets() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_scan_leaves_between_page_offsets"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), format!("v{}", i)))?;
+        }
+
+        let first = btree.get_page_for_key("k00")?;
+        let last = btree.get_page_for_key("k29")?;
+        let from = Offset(first.0.min(last.0));
+        let to = Offset(first.0.max(last.0) + PAGE_SIZE);
+        let scanned = btree.scan_between_pages(from, to)?;
+
+        let visible = btree.to_vec()?;
+        assert!(scanned.iter().any(|kv| kv.key == "k00"));
+        assert!(scanned.iter().any(|kv| kv.key == "k29"));
+        assert!(scanned.iter().all(|kv| visible.iter().any(|v| v.key == kv.key && v.value == kv.value)));
+        assert!(btree.scan_between_pages(Offset(0), Offset(0))?.is_empty());
+
+        btree.soft_delete("k03")?;
+        let leaf = btree.get_page_for_key("k03")?;
+        let scanned = btree.scan_between_pages(leaf.clone(), Offset(leaf.0 + PAGE_SIZE))?;
+        assert!(!scanned.is_empty());
+        assert!(scanned.iter().all(|kv| kv.key != "k03"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_offsets_depth_first() -> Result<(), Error> {
+        let mut btree = BTree::new(temp_path("should_list_offsets_depth_first"), 2)?;
+        for i in 0..30 {
+            btree.insert(KeyValuePair::new(format!("k{:02}", i), String::from("v")))?;
+        }
+
+        let offsets = btree.depth_first_offsets()?;
+        let (internal, leaf) = btree.node_count()?;
+        assert_eq!(offsets.len(), internal + leaf);
+        assert_eq!(offsets[0].0, btree.wal.get_root()?.0);
+        assert!(offsets.iter().all(|offset| offset.0 % PAGE_SIZE == 0));
 
+        let NodeType::Internal(children, _) = Node::try_from(btree.pager.get_page(&offsets[0])?)?.node_type else {
+            panic!("expected an internal root");
+        };
+        assert_eq!(offsets[1].0, children[0].0);
         Ok(())
     }
 }
@@ -1,11 +1,53 @@
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
   UnexpectedError,
   KeyOverflowError,
   ValueOverflowError,
   TryFromSliceError(String),
-  UTF8Error,
-  KeyNotFound,
+  UTF8Error(std::str::Utf8Error),
+  DatabaseLocked,
+  InvalidMagic,
+  CsvParseError(usize),
+  BranchingFactorOutOfRange { provided: usize, max: usize },
+  TreeCorrupted(String),
+  PageSizeMismatch { provided: usize, expected: usize },
+  PageWriteError(usize),
+  WalChecksumMismatch(u64),
+  PageOverflowError,
+  WalDirectoryInUse(std::path::PathBuf),
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Error::UnexpectedError => write!(f, "unexpected error"),
+      Error::KeyOverflowError => write!(f, "key is too long"),
+      Error::ValueOverflowError => write!(f, "value is too long"),
+      Error::TryFromSliceError(e) => write!(f, "could not convert slice: {}", e),
+      Error::UTF8Error(e) => write!(f, "invalid UTF-8 after {} valid bytes", e.valid_up_to()),
+      Error::DatabaseLocked => write!(f, "database is locked by another process"),
+      Error::InvalidMagic => write!(f, "page does not start with the expected magic number"),
+      Error::CsvParseError(line) => write!(f, "malformed CSV on line {}", line),
+      Error::BranchingFactorOutOfRange { provided, max } => {
+        write!(f, "branching factor {} is out of range 2..={}", provided, max)
+      }
+      Error::TreeCorrupted(msg) => write!(f, "tree is corrupted: {}", msg),
+      Error::PageSizeMismatch { provided, expected } => {
+        write!(f, "page size {} is not supported, pages are {} bytes", provided, expected)
+      }
+      Error::PageWriteError(offset) => write!(f, "could not write the page at offset {}", offset),
+      Error::WalChecksumMismatch(position) => write!(f, "WAL record at byte {} does not match its checksum", position),
+      Error::PageOverflowError => write!(f, "node does not fit in one page"),
+      Error::WalDirectoryInUse(dir) => write!(f, "{} already holds the WAL of the open tree", dir.display()),
+    }
+  }
+}
+
+impl std::convert::From<std::str::Utf8Error> for Error {
+  fn from(e: std::str::Utf8Error) -> Error {
+    Error::UTF8Error(e)
+  }
 }
 
 impl std::convert::From<std::io::Error> for Error {
@@ -0,0 +1,14 @@
+//! A copy-on-write B-tree stored in a single paged file with a write-ahead
+//! log next to it.
+//!
+//! The crate only builds on unix: pages and WAL records are read with
+//! positional reads from `std::os::unix::fs::FileExt`, and the pager hands
+//! out raw file descriptors.
+
+pub mod node;
+pub mod pager;
+pub mod page;
+pub mod btree;
+pub mod error;
+pub mod wal;
+pub mod utils;
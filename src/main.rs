@@ -1,11 +1,3 @@
-mod node;
-mod pager;
-mod page;
-mod btree;
-mod error;
-mod wal;
-mod utils;
-
 fn main() {
     println!("Hello, world!");
 }
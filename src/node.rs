@@ -1,15 +1,19 @@
-use std::str;
+use std::{collections::HashMap, str};
 
 use crate::{
     error::Error,
-    page::{Page, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET, PARENT_POINTER_OFFSET, PTR_SIZE},
-    utils::byte_to_bool
+    page::{
+        Page, INTERNAL_NODE_HEADER_SIZE, INTERNAL_NODE_NUM_CHILDREN_OFFSET, IS_ROOT_OFFSET, LEAF_KEY_SHARED_PREFIX_SIZE,
+        LEAF_DELETED_FLAG_SIZE, LEAF_EXPIRES_AT_SIZE, MAGIC_NUMBER, LEAF_KEY_SUFFIX_LEN_SIZE, LEAF_METADATA_LEN_SIZE, LEAF_NODE_HEADER_SIZE, LEAF_NODE_NUM_PAIRS_OFFSET, NODE_TYPE_OFFSET, PARENT_POINTER_OFFSET,
+        PTR_SIZE,
+    },
+    utils::{bool_to_byte, byte_to_bool, write_string, ByteReader},
 };
 
 pub const KEY_SIZE: usize = 10;
 pub const VALUE_SIZE: usize = 10;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Offset(pub usize);
 
 impl TryFrom<[u8; PTR_SIZE]> for Offset {
@@ -20,18 +24,75 @@ impl TryFrom<[u8; PTR_SIZE]> for Offset {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct Key(pub String);
 
-#[derive(Clone, Debug, Eq)]
+#[derive(Clone, Debug, Default, Eq)]
 pub struct KeyValuePair {
     pub key: String,
     pub value: String,
+    /// Unix timestamp (in seconds) after which the pair is treated as absent.
+    pub expires_at: Option<u64>,
+    /// Tombstone left by a soft delete; the pair stays on disk until compacted.
+    pub deleted: bool,
+    /// Free-form attributes stored next to the value, such as a version or source.
+    pub metadata: Option<HashMap<String, String>>,
 }
 
 impl KeyValuePair {
     pub fn new(key: String, value: String) -> Self {
-        Self { key, value }
+        Self { key, value, expires_at: None, deleted: false, metadata: None }
+    }
+
+    pub fn with_metadata(key: String, value: String, metadata: HashMap<String, String>) -> Self {
+        let mut pair = Self::new(key, value);
+        pair.metadata = (!metadata.is_empty()).then_some(metadata);
+        pair
+    }
+
+    pub fn set_metadata(&mut self, key: String, value: String) {
+        self.metadata.get_or_insert_with(HashMap::new).insert(key, value);
+    }
+
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.as_ref()?.get(key).map(String::as_str)
+    }
+
+    /// Removes `key` from the metadata, dropping the map once it is empty.
+    pub fn remove_metadata(&mut self, key: &str) -> Option<String> {
+        let metadata = self.metadata.as_mut()?;
+        let removed = metadata.remove(key);
+        if metadata.is_empty() {
+            self.metadata = None;
+        }
+        removed
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now > expires_at)
+    }
+}
+
+/// Reads a pair stored as a zero-padded key of `KEY_SIZE` bytes followed by
+/// a zero-padded value of `VALUE_SIZE` bytes.
+impl TryFrom<&[u8]> for KeyValuePair {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != KEY_SIZE + VALUE_SIZE {
+            return Err(Error::TryFromSliceError(format!(
+                "expected {} bytes for a key-value pair, got {}",
+                KEY_SIZE + VALUE_SIZE,
+                bytes.len()
+            )));
+        }
+        let (key_raw, value_raw) = bytes.split_at(KEY_SIZE);
+        let key = str::from_utf8(key_raw)?;
+        let value = str::from_utf8(value_raw)?;
+        Ok(KeyValuePair::new(
+            key.trim_matches(char::from(0)).to_string(),
+            value.trim_matches(char::from(0)).to_string(),
+        ))
     }
 }
 
@@ -53,6 +114,13 @@ impl PartialEq for KeyValuePair {
     }
 }
 
+/// Hashes the key only, to agree with `PartialEq`.
+impl std::hash::Hash for KeyValuePair {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum NodeType {
     Internal(Vec<Offset>, Vec<Key>),
@@ -60,6 +128,25 @@ pub enum NodeType {
     Unexpected,
 }
 
+impl NodeType {
+    /// Number of pairs in a leaf, or of separator keys in an internal node.
+    pub fn pair_count(&self) -> Result<usize, Error> {
+        match self {
+            NodeType::Internal(_, keys) => Ok(keys.len()),
+            NodeType::Leaf(pairs) => Ok(pairs.len()),
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+
+    pub fn child_count(&self) -> Result<usize, Error> {
+        match self {
+            NodeType::Internal(children, _) => Ok(children.len()),
+            NodeType::Leaf(_) => Ok(0),
+            NodeType::Unexpected => Err(Error::UnexpectedError),
+        }
+    }
+}
+
 impl From<&NodeType> for u8 {
     fn from(value: &NodeType) -> Self {
         match value {
@@ -80,6 +167,19 @@ impl From<u8> for NodeType {
     }
 }
 
+/// Where `Node::optimal_split_idx` puts the split point of a full node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SplitPolicy {
+    /// Splits in the middle, as the tree does on insert.
+    Balanced,
+    /// Keeps about 40% of the keys on the left, leaving room there for
+    /// workloads that insert in descending order.
+    LeftHeavy,
+    /// Keeps about 60% of the keys on the left, leaving room on the right
+    /// for append workloads.
+    RightHeavy,
+}
+
 #[derive(Clone, Debug)]
 pub struct Node {
     pub node_type: NodeType,
@@ -96,20 +196,139 @@ impl Node {
         }
     }
 
-    pub fn split(&mut self, branches: usize) -> Result<(Key, Node), Error> {
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.node_type, NodeType::Leaf(_))
+    }
+
+    /// Serializes the node without page padding: integers are big endian and
+    /// every string carries a u32 length prefix, so keys and values are not
+    /// limited to `KEY_SIZE` and `VALUE_SIZE`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![u8::from(&self.node_type), bool_to_byte(self.is_root)];
+        match &self.parent_offset {
+            Some(Offset(parent_offset)) => {
+                bytes.push(bool_to_byte(true));
+                bytes.extend_from_slice(&parent_offset.to_be_bytes());
+            }
+            None => bytes.push(bool_to_byte(false)),
+        }
+
+        match &self.node_type {
+            NodeType::Internal(children, keys) => {
+                bytes.extend_from_slice(&(children.len() as u32).to_be_bytes());
+                for Offset(child_offset) in children {
+                    bytes.extend_from_slice(&child_offset.to_be_bytes());
+                }
+                bytes.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+                for Key(key) in keys {
+                    write_string(&mut bytes, key);
+                }
+            }
+            NodeType::Leaf(pairs) => {
+                bytes.extend_from_slice(&(pairs.len() as u32).to_be_bytes());
+                for pair in pairs {
+                    write_string(&mut bytes, &pair.key);
+                    write_string(&mut bytes, &pair.value);
+                    bytes.extend_from_slice(&pair.expires_at.unwrap_or(0).to_be_bytes());
+                    bytes.push(bool_to_byte(pair.deleted));
+                    let mut metadata: Vec<_> = pair.metadata.iter().flatten().collect();
+                    metadata.sort();
+                    bytes.extend_from_slice(&(metadata.len() as u32).to_be_bytes());
+                    for (key, value) in metadata {
+                        write_string(&mut bytes, key);
+                        write_string(&mut bytes, value);
+                    }
+                }
+            }
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        }
+        Ok(bytes)
+    }
+
+    /// Reads a node written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Node, Error> {
+        let mut reader = ByteReader::new(bytes);
+        let [node_type, is_root, has_parent] = reader.read_array()?;
+        let parent_offset = if byte_to_bool(has_parent) {
+            Some(Offset(usize::from_be_bytes(reader.read_array()?)))
+        } else {
+            None
+        };
+
+        let node_type = match NodeType::from(node_type) {
+            NodeType::Internal(mut children, mut keys) => {
+                for _ in 0..u32::from_be_bytes(reader.read_array()?) {
+                    children.push(Offset(usize::from_be_bytes(reader.read_array()?)));
+                }
+                for _ in 0..u32::from_be_bytes(reader.read_array()?) {
+                    keys.push(Key(reader.read_string()?));
+                }
+                NodeType::Internal(children, keys)
+            }
+            NodeType::Leaf(mut pairs) => {
+                for _ in 0..u32::from_be_bytes(reader.read_array()?) {
+                    let mut pair = KeyValuePair::new(reader.read_string()?, reader.read_string()?);
+                    let expires_at = u64::from_be_bytes(reader.read_array()?);
+                    pair.expires_at = (expires_at != 0).then_some(expires_at);
+                    let [deleted] = reader.read_array()?;
+                    pair.deleted = byte_to_bool(deleted);
+                    for _ in 0..u32::from_be_bytes(reader.read_array()?) {
+                        pair.set_metadata(reader.read_string()?, reader.read_string()?);
+                    }
+                    pairs.push(pair);
+                }
+                NodeType::Leaf(pairs)
+            }
+            NodeType::Unexpected => return Err(Error::UnexpectedError),
+        };
+        if !reader.is_empty() {
+            return Err(Error::UnexpectedError);
+        }
+        Ok(Node::new(node_type, byte_to_bool(is_root), parent_offset))
+    }
+
+    /// Re-serializes the node into a fresh page, with its entries packed right
+    /// after the header and every byte past them zeroed. Whatever a previous
+    /// page left behind past the last entry is dropped.
+    pub fn repack(&self) -> Result<Page, Error> {
+        Page::try_from(self)
+    }
+
+    /// Index of the child that `key` belongs to in an internal node. Keys
+    /// equal to a separator belong to the child on its left.
+    pub fn find_child_index(&self, key: &str) -> Result<usize, Error> {
+        match &self.node_type {
+            NodeType::Internal(_, keys) => Ok(keys.partition_point(|Key(separator)| separator.as_str() < key)),
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    pub fn key_count(&self) -> usize {
+        match &self.node_type {
+            NodeType::Internal(_, keys) => keys.len(),
+            NodeType::Leaf(pairs) => pairs.len(),
+            NodeType::Unexpected => 0,
+        }
+    }
+
+    /// Moves everything after the first `split_idx` children (internal) or
+    /// pairs (leaf) into a new right sibling, and returns the separator that
+    /// goes between them: the key after the kept ones for internal nodes,
+    /// which leaves the node, or the last kept key for leaves.
+    pub fn split(&mut self, split_idx: usize) -> Result<(Key, Node), Error> {
         match self.node_type {
             NodeType::Internal(ref mut children, ref mut keys) => {
-                let mut sibling_keys = keys.split_off(branches - 1);
+                let mut sibling_keys = keys.split_off(split_idx - 1);
                 let median_key = sibling_keys.remove(0);
-                let sibling_children = children.split_off(branches);
+                let sibling_children = children.split_off(split_idx);
                 Ok((
                     median_key,
                     Node::new(NodeType::Internal(sibling_children, sibling_keys), false, self.parent_offset.clone())
                 ))
             }
             NodeType::Leaf(ref mut pairs) => {
-                let sibling_pairs = pairs.split_off(branches);
-                let median_pair = pairs.get(branches - 1).ok_or(Error::UnexpectedError)?.clone();
+                let sibling_pairs = pairs.split_off(split_idx);
+                let median_pair = pairs.get(split_idx - 1).ok_or(Error::UnexpectedError)?.clone();
                 Ok((
                     Key(median_pair.key.clone()),
                     Node::new(NodeType::Leaf(sibling_pairs), false, self.parent_offset.clone())
@@ -118,21 +337,102 @@ impl Node {
             NodeType::Unexpected => Err(Error::UnexpectedError),
         }
     }
+
+    /// The `split_idx` to pass to `split` for a full node, one holding
+    /// `2 * branches - 1` keys, under `policy`.
+    pub fn optimal_split_idx(branches: usize, policy: SplitPolicy) -> usize {
+        let full = 2 * branches - 1;
+        match policy {
+            SplitPolicy::Balanced => branches,
+            SplitPolicy::LeftHeavy => (full * 2 / 5).clamp(1, branches),
+            SplitPolicy::RightHeavy => (full * 3 / 5).clamp(branches, full.saturating_sub(1).max(branches)),
+        }
+    }
+
+    /// Appends `right` to this node. For internal nodes, `separator` is pulled
+    /// down between the two key lists; leaves simply drop it.
+    pub fn merge(&mut self, separator: Key, right: Node) -> Result<(), Error> {
+        match (&mut self.node_type, right.node_type) {
+            (NodeType::Internal(ref mut children, ref mut keys), NodeType::Internal(right_children, right_keys)) => {
+                keys.push(separator);
+                keys.extend(right_keys);
+                children.extend(right_children);
+                Ok(())
+            }
+            (NodeType::Leaf(ref mut pairs), NodeType::Leaf(right_pairs)) => {
+                pairs.extend(right_pairs);
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// Moves the last entry of the left sibling to the front of this node and
+    /// returns the separator that should now sit between them.
+    pub fn take_last_from(&mut self, left: &mut Node, separator: Key) -> Result<Key, Error> {
+        match (&mut self.node_type, &mut left.node_type) {
+            (NodeType::Internal(ref mut children, ref mut keys), NodeType::Internal(ref mut left_children, ref mut left_keys)) => {
+                keys.insert(0, separator);
+                children.insert(0, left_children.pop().ok_or(Error::UnexpectedError)?);
+                left_keys.pop().ok_or(Error::UnexpectedError)
+            }
+            (NodeType::Leaf(ref mut pairs), NodeType::Leaf(ref mut left_pairs)) => {
+                pairs.insert(0, left_pairs.pop().ok_or(Error::UnexpectedError)?);
+                let last = left_pairs.last().ok_or(Error::UnexpectedError)?;
+                Ok(Key(last.key.clone()))
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// Moves the first entry of the right sibling to the end of this node and
+    /// returns the separator that should now sit between them.
+    pub fn take_first_from(&mut self, right: &mut Node, separator: Key) -> Result<Key, Error> {
+        match (&mut self.node_type, &mut right.node_type) {
+            (NodeType::Internal(ref mut children, ref mut keys), NodeType::Internal(ref mut right_children, ref mut right_keys)) => {
+                if right_keys.is_empty() {
+                    return Err(Error::UnexpectedError);
+                }
+                keys.push(separator);
+                children.push(right_children.remove(0));
+                Ok(right_keys.remove(0))
+            }
+            (NodeType::Leaf(ref mut pairs), NodeType::Leaf(ref mut right_pairs)) => {
+                if right_pairs.is_empty() {
+                    return Err(Error::UnexpectedError);
+                }
+                let pair = right_pairs.remove(0);
+                let separator = Key(pair.key.clone());
+                pairs.push(pair);
+                Ok(separator)
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node::new(NodeType::Leaf(vec![]), false, None)
+    }
 }
 
 impl TryFrom<Page> for Node {
     type Error = Error;
 
     fn try_from(value: Page) -> Result<Self, Self::Error> {
+        if value.magic_number() != MAGIC_NUMBER {
+            return Err(Error::InvalidMagic);
+        }
+
         let raw = value.get_data();
         let node_type = NodeType::from(raw[NODE_TYPE_OFFSET]);
         let is_root = byte_to_bool(raw[IS_ROOT_OFFSET]);
-        let parent_offset: Option<Offset>;
-        if is_root {
-            parent_offset = None;
+        let parent_offset = if is_root {
+            None
         } else {
-            parent_offset = Some(Offset(value.get_value_from_offset(PARENT_POINTER_OFFSET)?));
-        }
+            Some(Offset(value.get_value_from_offset(PARENT_POINTER_OFFSET)?))
+        };
 
         match node_type {
             NodeType::Internal(mut children, mut keys) => {
@@ -146,10 +446,8 @@ impl TryFrom<Page> for Node {
                 }
 
                 for _i in 1..num_children {
-                    let key_raw = value.get_ptr_from_offset(offset, KEY_SIZE);
-                    let Ok(key) = str::from_utf8(key_raw) else {
-                        return Err(Error::UTF8Error);
-                    };
+                    let key_raw = read_field(&raw, offset, KEY_SIZE)?;
+                    let key = str::from_utf8(key_raw)?;
                     offset += KEY_SIZE;
                     keys.push(Key(key.trim_matches(char::from(0)).to_string()));
                 }
@@ -164,25 +462,50 @@ impl TryFrom<Page> for Node {
                 let num_keys_val_pairs = value.get_value_from_offset(offset)?;
                 offset = LEAF_NODE_HEADER_SIZE;
 
+                let mut previous_key: Vec<u8> = Vec::new();
                 for _i in 1..=num_keys_val_pairs {
-                    let key_raw = value.get_ptr_from_offset(offset, KEY_SIZE);
-                    let Ok(key) = str::from_utf8(key_raw) else {
-                        return Err(Error::UTF8Error);
-                    };
-                    offset += KEY_SIZE;
+                    let shared = read_field(&raw, offset, LEAF_KEY_SHARED_PREFIX_SIZE)?[0] as usize;
+                    offset += LEAF_KEY_SHARED_PREFIX_SIZE;
+                    let suffix_len = read_field(&raw, offset, LEAF_KEY_SUFFIX_LEN_SIZE)?[0] as usize;
+                    offset += LEAF_KEY_SUFFIX_LEN_SIZE;
 
-                    let value_raw = value.get_ptr_from_offset(offset, VALUE_SIZE);
-                    let Ok(value) = str::from_utf8(value_raw) else {
-                        return Err(Error::UTF8Error);
+                    let Some(prefix) = previous_key.get(..shared) else {
+                        return Err(Error::TreeCorrupted(format!("key at byte {} shares more than the previous key", offset)));
                     };
+                    let mut key_raw = prefix.to_vec();
+                    key_raw.extend_from_slice(read_field(&raw, offset, suffix_len)?);
+                    offset += suffix_len;
+                    let key = str::from_utf8(&key_raw)?;
+
+                    let value_raw = read_field(&raw, offset, VALUE_SIZE)?;
+                    let value = str::from_utf8(value_raw)?;
                     offset += VALUE_SIZE;
 
-                    pairs.push(
-                        KeyValuePair::new(
-                            key.trim_matches(char::from(0)).to_string(),
-                            value.trim_matches(char::from(0)).to_string(),
-                        ),
+                    let metadata_len = read_field(&raw, offset, LEAF_METADATA_LEN_SIZE)?;
+                    let metadata_len = u16::from_be_bytes([metadata_len[0], metadata_len[1]]) as usize;
+                    offset += LEAF_METADATA_LEN_SIZE;
+                    let metadata = decode_metadata(read_field(&raw, offset, metadata_len)?)?;
+                    offset += metadata_len;
+
+                    let mut expires_at = [0x00; LEAF_EXPIRES_AT_SIZE];
+                    expires_at.clone_from_slice(read_field(&raw, offset, LEAF_EXPIRES_AT_SIZE)?);
+                    let expires_at = u64::from_be_bytes(expires_at);
+                    offset += LEAF_EXPIRES_AT_SIZE;
+
+                    let deleted = byte_to_bool(read_field(&raw, offset, LEAF_DELETED_FLAG_SIZE)?[0]);
+                    offset += LEAF_DELETED_FLAG_SIZE;
+
+                    let mut pair = KeyValuePair::new(
+                        key.to_string(),
+                        value.trim_matches(char::from(0)).to_string(),
                     );
+                    if expires_at != 0 {
+                        pair.expires_at = Some(expires_at);
+                    }
+                    pair.deleted = deleted;
+                    pair.metadata = metadata;
+                    pairs.push(pair);
+                    previous_key = key_raw;
                 }
                 Ok(Node::new(NodeType::Leaf(pairs), is_root, parent_offset))
             }
@@ -190,3 +513,196 @@ impl TryFrom<Page> for Node {
         }
     }
 }
+
+/// The `len` bytes of `raw` starting at `offset`, or `TreeCorrupted` if a
+/// length read from the page points past its end.
+fn read_field(raw: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    raw.get(offset..offset + len)
+        .ok_or_else(|| Error::TreeCorrupted(format!("field at byte {} runs past the end of the page", offset)))
+}
+
+fn decode_metadata(mut bytes: &[u8]) -> Result<Option<HashMap<String, String>>, Error> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+
+    let mut metadata = HashMap::new();
+    while !bytes.is_empty() {
+        let key = read_metadata_field(&mut bytes)?;
+        let value = read_metadata_field(&mut bytes)?;
+        metadata.insert(key, value);
+    }
+    Ok(Some(metadata))
+}
+
+fn read_metadata_field(bytes: &mut &[u8]) -> Result<String, Error> {
+    let (&len, rest) = bytes.split_first().ok_or(Error::UnexpectedError)?;
+    let field = rest.get(..len as usize).ok_or(Error::UnexpectedError)?;
+    *bytes = &rest[len as usize..];
+    Ok(str::from_utf8(field)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PAGE_SIZE;
+
+    #[test]
+    fn should_default_to_empty_leaf() {
+        let node = Node::default();
+
+        assert!(node.is_leaf());
+        assert_eq!(node.key_count(), 0);
+        assert!(!node.is_root);
+        assert!(node.parent_offset.is_none());
+        assert_eq!(KeyValuePair::default(), KeyValuePair::new(String::new(), String::new()));
+        assert_eq!(Offset::default().0, 0);
+    }
+
+    #[test]
+    fn should_count_pairs_and_children() -> Result<(), Error> {
+        let leaf = NodeType::Leaf(vec![KeyValuePair::new(String::from("a"), String::from("b"))]);
+        assert_eq!(leaf.pair_count()?, 1);
+        assert_eq!(leaf.child_count()?, 0);
+
+        let internal = NodeType::Internal(vec![Offset(0), Offset(4096)], vec![Key(String::from("a"))]);
+        assert_eq!(internal.pair_count()?, 1);
+        assert_eq!(internal.child_count()?, 2);
+
+        assert!(NodeType::Unexpected.pair_count().is_err());
+        assert!(NodeType::Unexpected.child_count().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_split_at_policy_index() -> Result<(), Error> {
+        let branches = 10;
+        let pairs: Vec<_> = (0..2 * branches - 1).map(|i| KeyValuePair::new(format!("k{:02}", i), String::from("v"))).collect();
+        let keys: Vec<_> = (0..2 * branches - 1).map(|i| Key(format!("k{:02}", i))).collect();
+        let children: Vec<_> = (0..2 * branches).map(|i| Offset(i * PAGE_SIZE)).collect();
+
+        for (policy, split_idx) in [(SplitPolicy::Balanced, 10), (SplitPolicy::LeftHeavy, 7), (SplitPolicy::RightHeavy, 11)] {
+            assert_eq!(Node::optimal_split_idx(branches, policy), split_idx);
+
+            let mut leaf = Node::new(NodeType::Leaf(pairs.clone()), false, None);
+            let (median, sibling) = leaf.split(split_idx)?;
+            assert_eq!(median.0, format!("k{:02}", split_idx - 1));
+            assert_eq!((leaf.key_count(), sibling.key_count()), (split_idx, 2 * branches - 1 - split_idx));
+
+            let mut internal = Node::new(NodeType::Internal(children.clone(), keys.clone()), false, None);
+            let (median, sibling) = internal.split(split_idx)?;
+            assert_eq!(median.0, format!("k{:02}", split_idx - 1));
+            assert_eq!(internal.node_type.child_count()? + sibling.node_type.child_count()?, 2 * branches);
+            assert_eq!(internal.node_type.child_count()?, split_idx);
+        }
+
+        assert_eq!(Node::optimal_split_idx(2, SplitPolicy::LeftHeavy), 1);
+        assert_eq!(Node::optimal_split_idx(2, SplitPolicy::RightHeavy), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn should_hash_pairs_by_key_only() {
+        use std::collections::HashSet;
+
+        let one = KeyValuePair::new(String::from("a"), String::from("one"));
+        let mut two = KeyValuePair::new(String::from("a"), String::from("two"));
+        two.deleted = true;
+
+        let set: HashSet<KeyValuePair> = [one.clone(), two.clone()].into_iter().collect();
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.iter().next().unwrap().value, "one");
+
+        let mut counts: HashMap<KeyValuePair, usize> = HashMap::new();
+        for pair in [one, two, KeyValuePair::new(String::from("b"), String::from("one"))] {
+            *counts.entry(pair).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&KeyValuePair::new(String::from("a"), String::new())], 2);
+
+        let keys: HashSet<Key> = [Key(String::from("a")), Key(String::from("a")), Key(String::from("b"))].into_iter().collect();
+        assert_eq!(keys.len(), 2);
+    }
+
+    #[test]
+    fn should_find_child_index_around_separators() {
+        let separators = vec![Key(String::from("d")), Key(String::from("m"))];
+        let node = Node::new(NodeType::Internal(vec![Offset(0), Offset(1), Offset(2)], separators), true, None);
+        assert_eq!(node.find_child_index("a").unwrap(), 0);
+        assert_eq!(node.find_child_index("d").unwrap(), 0);
+        assert_eq!(node.find_child_index("e").unwrap(), 1);
+        assert_eq!(node.find_child_index("m").unwrap(), 1);
+        assert_eq!(node.find_child_index("z").unwrap(), 2);
+        assert!(matches!(Node::default().find_child_index("a"), Err(Error::UnexpectedError)));
+    }
+
+    #[test]
+    fn should_decode_pair_from_fixed_width_bytes() {
+        let mut raw = [0x00; KEY_SIZE + VALUE_SIZE];
+        raw[..3].copy_from_slice(b"key");
+        raw[KEY_SIZE..KEY_SIZE + 5].copy_from_slice(b"value");
+        let pair = KeyValuePair::try_from(&raw[..]).unwrap();
+        assert_eq!((pair.key.as_str(), pair.value.as_str()), ("key", "value"));
+
+        assert!(matches!(KeyValuePair::try_from(&raw[1..]), Err(Error::TryFromSliceError(_))));
+        raw[0] = 0xFF;
+        assert!(matches!(KeyValuePair::try_from(&raw[..]), Err(Error::UTF8Error(_))));
+    }
+
+    #[test]
+    fn should_round_trip_compact_bytes() {
+        let keys = vec![Key(String::from("a much longer separator")), Key(String::from("m"))];
+        let internal = Node::new(NodeType::Internal(vec![Offset(4096), Offset(8192), Offset(12288)], keys), true, None);
+
+        let mut pair = KeyValuePair::new(String::from("longer than ten bytes"), String::from("and so is this value"));
+        pair.expires_at = Some(1_700_000_000);
+        pair.deleted = true;
+        pair.set_metadata(String::from("source"), String::from("import"));
+        let plain = KeyValuePair::new(String::from("z"), String::new());
+        let leaf = Node::new(NodeType::Leaf(vec![pair, plain]), false, Some(Offset(4096)));
+
+        for node in [internal, leaf, Node::default()] {
+            let bytes = node.to_bytes().unwrap();
+            let decoded = Node::from_bytes(&bytes).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", node));
+            assert!(Node::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn should_repack_page_without_stale_bytes() {
+        let pairs = vec![
+            KeyValuePair::new(String::from("a"), String::from("1")),
+            KeyValuePair::new(String::from("b"), String::from("2")),
+        ];
+        let mut data = Page::try_from(&Node::new(NodeType::Leaf(pairs), true, None)).unwrap().get_data();
+        data[PAGE_SIZE - 64..].fill(0xAB);
+
+        let node = Node::try_from(Page::new(data)).unwrap();
+        let repacked = node.repack().unwrap();
+        assert!(repacked.get_data()[PAGE_SIZE - 64..].iter().all(|&byte| byte == 0));
+        assert_eq!(format!("{:?}", Node::try_from(repacked).unwrap()), format!("{:?}", node));
+    }
+
+    #[test]
+    fn should_reject_fields_running_past_the_page() -> Result<(), Error> {
+        let pairs = vec![KeyValuePair::new(String::from("a"), String::from("one"))];
+        let data = Page::try_from(&Node::new(NodeType::Leaf(pairs), true, None))?.get_data();
+
+        let metadata_len_offset = LEAF_NODE_HEADER_SIZE + LEAF_KEY_SHARED_PREFIX_SIZE + LEAF_KEY_SUFFIX_LEN_SIZE + 1 + VALUE_SIZE;
+        let mut corrupted = data;
+        corrupted[metadata_len_offset..metadata_len_offset + LEAF_METADATA_LEN_SIZE].clone_from_slice(&u16::MAX.to_be_bytes());
+        assert!(matches!(Node::try_from(Page::new(corrupted)), Err(Error::TreeCorrupted(_))));
+
+        // A pair count far beyond what the page holds makes the decoder read
+        // zeroed pairs until the next field is cut off by the page end.
+        let mut corrupted = data;
+        corrupted[LEAF_NODE_NUM_PAIRS_OFFSET..LEAF_NODE_NUM_PAIRS_OFFSET + PTR_SIZE].clone_from_slice(&usize::MAX.to_be_bytes());
+        assert!(matches!(Node::try_from(Page::new(corrupted)), Err(Error::TreeCorrupted(_))));
+
+        let mut corrupted = data;
+        corrupted[LEAF_NODE_HEADER_SIZE] = 5;
+        assert!(matches!(Node::try_from(Page::new(corrupted)), Err(Error::TreeCorrupted(_))));
+        Ok(())
+    }
+}
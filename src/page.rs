@@ -2,7 +2,7 @@ use std::mem::size_of;
 
 use crate::{
     error::Error,
-    node::{Key, Node, NodeType, Offset, KEY_SIZE, VALUE_SIZE},
+    node::{Key, KeyValuePair, Node, NodeType, Offset, KEY_SIZE, VALUE_SIZE},
     utils::bool_to_byte,
 };
 
@@ -11,13 +11,18 @@ use crate::{
 pub const PAGE_SIZE: usize = 4096;
 pub const PTR_SIZE: usize = size_of::<usize>(); // 8 bytes on 64-bit systems
 
+/// Every page starts with these bytes so that foreign files are rejected early.
+pub const MAGIC_NUMBER: [u8; MAGIC_NUMBER_SIZE] = *b"BERS";
+pub const MAGIC_NUMBER_SIZE: usize = 4;
+pub const MAGIC_NUMBER_OFFSET: usize = 0;
+
 pub const IS_ROOT_SIZE: usize = 1;
-pub const IS_ROOT_OFFSET: usize = 0;
-pub const PARENT_POINTER_OFFSET: usize = 2;
+pub const IS_ROOT_OFFSET: usize = MAGIC_NUMBER_OFFSET + MAGIC_NUMBER_SIZE;
+pub const PARENT_POINTER_OFFSET: usize = NODE_TYPE_OFFSET + NODE_TYPE_SIZE;
 pub const PARENT_POINTER_SIZE: usize = PTR_SIZE;
 pub const NODE_TYPE_SIZE: usize = 1;
-pub const NODE_TYPE_OFFSET: usize = 1;
-pub const COMMON_NODE_HEADER_SIZE: usize = NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
+pub const NODE_TYPE_OFFSET: usize = IS_ROOT_OFFSET + IS_ROOT_SIZE;
+pub const COMMON_NODE_HEADER_SIZE: usize = MAGIC_NUMBER_SIZE + NODE_TYPE_SIZE + IS_ROOT_SIZE + PARENT_POINTER_SIZE;
 
 pub const INTERNAL_NODE_NUM_CHILDREN_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 pub const INTERNAL_NODE_NUM_CHILDREN_SIZE: usize = PTR_SIZE;
@@ -27,6 +32,37 @@ pub const LEAF_NODE_NUM_PAIRS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 pub const LEAF_NODE_NUM_PAIRS_SIZE: usize = PTR_SIZE;
 pub const LEAF_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE + LEAF_NODE_NUM_PAIRS_SIZE;
 
+/// Leaf keys are delta encoded against the previous key in the page: a byte
+/// with the length of the shared prefix, a byte with the length of the rest,
+/// then the rest of the key itself.
+pub const LEAF_KEY_SHARED_PREFIX_SIZE: usize = 1;
+pub const LEAF_KEY_SUFFIX_LEN_SIZE: usize = 1;
+/// Byte length of the metadata section stored after each leaf value. The
+/// section holds `[key len u8][key][value len u8][value]` per entry, sorted
+/// by key, and is empty for pairs without metadata.
+pub const LEAF_METADATA_LEN_SIZE: usize = 2;
+/// Unix timestamp stored after each leaf value, zero meaning it never expires.
+pub const LEAF_EXPIRES_AT_SIZE: usize = 8;
+pub const LEAF_DELETED_FLAG_SIZE: usize = 1;
+
+/// Largest leaf pair without metadata: full-length key and value.
+const MAX_LEAF_PAIR_SIZE: usize = LEAF_KEY_SHARED_PREFIX_SIZE
+    + LEAF_KEY_SUFFIX_LEN_SIZE
+    + KEY_SIZE
+    + VALUE_SIZE
+    + LEAF_METADATA_LEN_SIZE
+    + LEAF_EXPIRES_AT_SIZE
+    + LEAF_DELETED_FLAG_SIZE;
+/// Largest branching factor `b` whose full nodes still fit in a page: a leaf
+/// with `2b - 1` maximum-size pairs, and an internal node with `2b` children
+/// and `2b - 1` keys. Metadata is not counted; a leaf that runs out of room
+/// because of it fails to encode.
+pub const MAX_BRANCHES_PER_PAGE: usize = {
+    let leaf = ((PAGE_SIZE - LEAF_NODE_HEADER_SIZE) / MAX_LEAF_PAIR_SIZE).div_ceil(2);
+    let internal = (PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE + KEY_SIZE) / (2 * (PTR_SIZE + KEY_SIZE));
+    if leaf < internal { leaf } else { internal }
+};
+
 type PageData = [u8; PAGE_SIZE];
 
 /// This is a wrapper for a value in a given page
@@ -49,7 +85,6 @@ impl TryFrom<&[u8]> for Value {
     }
 }
 
-#[derive(Debug)]
 pub struct Page {
     data: Box<PageData>,
 }
@@ -66,7 +101,10 @@ impl Page {
     }
 
     pub fn get_value_from_offset(&self, offset: usize) -> Result<usize, Error> {
-        let bytes = &self.data[offset..offset + PTR_SIZE];
+        let bytes = self
+            .data
+            .get(offset..offset + PTR_SIZE)
+            .ok_or_else(|| Error::TreeCorrupted(format!("field at byte {} runs past the end of the page", offset)))?;
         let Value(res) = Value::try_from(bytes)?;
         Ok(res)
     }
@@ -74,6 +112,45 @@ impl Page {
     pub fn get_ptr_from_offset(&self, offset: usize, size: usize) -> &[u8] {
         &self.data[offset..offset + size]
     }
+
+    pub fn magic_number(&self) -> &[u8] {
+        self.get_ptr_from_offset(MAGIC_NUMBER_OFFSET, MAGIC_NUMBER_SIZE)
+    }
+
+    /// Overwrites the bytes starting at `offset` with `bytes`, e.g. to put a
+    /// before-image back. Fails if the range runs past the end of the page.
+    pub fn patch_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+        let end = offset.checked_add(bytes.len()).ok_or(Error::UnexpectedError)?;
+        if end > PAGE_SIZE {
+            return Err(Error::UnexpectedError);
+        }
+        self.data[offset..end].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Number of bytes from the start of the page shown by `Debug`.
+const DEBUG_PREVIEW_SIZE: usize = 32;
+
+impl std::fmt::Debug for Page {
+    /// Shows the header fields as stored, without decoding the node, so
+    /// zeroed or corrupted pages format too.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let node_type = match self.data[NODE_TYPE_OFFSET] {
+            0x01 => String::from("Internal"),
+            0x02 => String::from("Leaf"),
+            byte => format!("Unknown(0x{:02x})", byte),
+        };
+        let preview: Vec<String> = self.data[..DEBUG_PREVIEW_SIZE].iter().map(|byte| format!("{:02x}", byte)).collect();
+        f.debug_struct("Page")
+            .field("magic", &String::from_utf8_lossy(self.magic_number()))
+            .field("is_root", &(self.data[IS_ROOT_OFFSET] != 0))
+            .field("node_type", &format_args!("{}", node_type))
+            .field("parent_offset", &self.get_value_from_offset(PARENT_POINTER_OFFSET).unwrap_or_default())
+            .field("count", &self.get_value_from_offset(COMMON_NODE_HEADER_SIZE).unwrap_or_default())
+            .field("data", &format_args!("{}..", preview.join(" ")))
+            .finish()
+    }
 }
 
 impl TryFrom<&Node> for Page {
@@ -81,6 +158,7 @@ impl TryFrom<&Node> for Page {
 
     fn try_from(node: &Node) -> Result<Self, Self::Error> {
         let mut data: PageData = [0x00; PAGE_SIZE];
+        data[MAGIC_NUMBER_OFFSET..MAGIC_NUMBER_OFFSET + MAGIC_NUMBER_SIZE].clone_from_slice(&MAGIC_NUMBER);
         data[IS_ROOT_OFFSET] = bool_to_byte(node.is_root);
         data[NODE_TYPE_OFFSET] = u8::from(&node.node_type);
 
@@ -93,10 +171,6 @@ impl TryFrom<&Node> for Page {
                 .clone_from_slice(&parent_offset.to_be_bytes());
         }
 
-        if !node.is_root {
-        
-        }
-
         match &node.node_type {
             NodeType::Internal(child_offsets, keys) => {
                 data[INTERNAL_NODE_NUM_CHILDREN_OFFSET..INTERNAL_NODE_NUM_CHILDREN_OFFSET + INTERNAL_NODE_NUM_CHILDREN_SIZE]
@@ -104,10 +178,9 @@ impl TryFrom<&Node> for Page {
 
                 let mut page_offset = INTERNAL_NODE_HEADER_SIZE;
 
-                child_offsets.iter().for_each(|Offset(child_offset)| {
-                    data[page_offset..page_offset + PTR_SIZE].clone_from_slice(&child_offset.to_be_bytes());
-                    page_offset += PTR_SIZE;
-                });
+                for Offset(child_offset) in child_offsets {
+                    write_at(&mut data, &mut page_offset, &child_offset.to_be_bytes())?;
+                }
 
                 for Key(key) in keys {
                     let key_bytes = key.as_bytes();
@@ -117,12 +190,8 @@ impl TryFrom<&Node> for Page {
                         return Err(Error::KeyOverflowError);
                     }
 
-                    key_bytes.iter().enumerate().for_each(|(i, &byte)| {
-                        raw_key[i] = byte;
-                    });
-
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&raw_key);
-                    page_offset += KEY_SIZE;
+                    raw_key[..key_bytes.len()].clone_from_slice(key_bytes);
+                    write_at(&mut data, &mut page_offset, &raw_key)?;
                 }
             }
             NodeType::Leaf(key_value_pairs) => {
@@ -130,34 +199,36 @@ impl TryFrom<&Node> for Page {
                     .clone_from_slice(&key_value_pairs.len().to_be_bytes());
 
                 let mut page_offset = LEAF_NODE_HEADER_SIZE;
+                let mut previous_key: &[u8] = &[];
                 for pair in key_value_pairs {
                     let key_bytes = pair.key.as_bytes();
-                    let mut raw_key: [u8; KEY_SIZE] = [0x00; KEY_SIZE];
 
                     if key_bytes.len() > KEY_SIZE {
                         return Err(Error::KeyOverflowError);
                     }
 
-                    key_bytes.iter().enumerate().for_each(|(i, &byte)| {
-                        raw_key[i] = byte;
-                    });
+                    let shared = key_bytes.iter().zip(previous_key).take_while(|(a, b)| a == b).count();
+                    let suffix = &key_bytes[shared..];
 
-                    data[page_offset..page_offset + KEY_SIZE].clone_from_slice(&raw_key);
-                    page_offset += KEY_SIZE;
+                    write_at(&mut data, &mut page_offset, &[shared as u8, suffix.len() as u8])?;
+                    write_at(&mut data, &mut page_offset, suffix)?;
+                    previous_key = key_bytes;
 
                     let value_bytes = pair.value.as_bytes();
                     let mut raw_value: [u8; VALUE_SIZE] = [0x00; VALUE_SIZE];
-                    
+
                     if value_bytes.len() > VALUE_SIZE {
                         return Err(Error::ValueOverflowError);
                     }
 
-                    value_bytes.iter().enumerate().for_each(|(i, &byte)| {
-                        raw_value[i] = byte;
-                    });
+                    raw_value[..value_bytes.len()].clone_from_slice(value_bytes);
+                    write_at(&mut data, &mut page_offset, &raw_value)?;
 
-                    data[page_offset..page_offset + VALUE_SIZE].clone_from_slice(&raw_value);
-                    page_offset += VALUE_SIZE;
+                    let metadata = encode_metadata(pair)?;
+                    write_at(&mut data, &mut page_offset, &(metadata.len() as u16).to_be_bytes())?;
+                    write_at(&mut data, &mut page_offset, &metadata)?;
+                    write_at(&mut data, &mut page_offset, &pair.expires_at.unwrap_or(0).to_be_bytes())?;
+                    write_at(&mut data, &mut page_offset, &[bool_to_byte(pair.deleted)])?;
                 }
             }
             NodeType::Unexpected => return Err(Error::UnexpectedError),
@@ -166,3 +237,202 @@ impl TryFrom<&Node> for Page {
         Ok(Self::new(data))
     }
 }
+
+/// Copies `bytes` into `data` at `offset` and moves `offset` past them, or
+/// fails if they would run off the end of the page.
+fn write_at(data: &mut PageData, offset: &mut usize, bytes: &[u8]) -> Result<(), Error> {
+    let end = *offset + bytes.len();
+    data.get_mut(*offset..end).ok_or(Error::PageOverflowError)?.clone_from_slice(bytes);
+    *offset = end;
+    Ok(())
+}
+
+fn encode_metadata(pair: &KeyValuePair) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    let Some(metadata) = &pair.metadata else {
+        return Ok(bytes);
+    };
+
+    let mut entries: Vec<_> = metadata.iter().collect();
+    entries.sort();
+    for (key, value) in entries {
+        for field in [key, value] {
+            let len = u8::try_from(field.len()).map_err(|_| Error::ValueOverflowError)?;
+            bytes.push(len);
+            bytes.extend_from_slice(field.as_bytes());
+        }
+    }
+    if bytes.len() > u16::MAX as usize {
+        return Err(Error::ValueOverflowError);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn round_trip(keys: &[&str]) -> Result<Vec<KeyValuePair>, Error> {
+        let pairs: Vec<KeyValuePair> = keys
+            .iter()
+            .map(|key| KeyValuePair::new(key.to_string(), format!("v-{}", key.len())))
+            .collect();
+        let node = Node::new(NodeType::Leaf(pairs), true, None);
+        let NodeType::Leaf(decoded) = Node::try_from(Page::try_from(&node)?)?.node_type else {
+            return Err(Error::UnexpectedError);
+        };
+        Ok(decoded)
+    }
+
+    #[test]
+    fn should_reject_page_without_magic_number() -> Result<(), Error> {
+        let page = Page::try_from(&Node::new(NodeType::Leaf(vec![]), true, None))?;
+        assert_eq!(page.magic_number(), b"BERS");
+
+        let mut data = page.get_data();
+        data[MAGIC_NUMBER_OFFSET] = 0xFF;
+        assert!(matches!(Node::try_from(Page::new(data)), Err(Error::InvalidMagic)));
+        Ok(())
+    }
+
+    #[test]
+    fn should_round_trip_prefix_compressed_keys() -> Result<(), Error> {
+        for keys in [
+            vec!["apple", "banana", "cherry"],
+            vec!["user:1", "user:2", "usxx:3"],
+            vec!["aaaaaaaaa1", "aaaaaaaaa2", "aaaaaaaaa3"],
+            vec!["a", "ab", "abc", "b"],
+        ] {
+            let decoded = round_trip(&keys)?;
+            let decoded_keys: Vec<&str> = decoded.iter().map(|pair| pair.key.as_str()).collect();
+            assert_eq!(decoded_keys, keys);
+            for pair in decoded {
+                assert_eq!(pair.value, format!("v-{}", pair.key.len()));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_round_trip_metadata() -> Result<(), Error> {
+        let mut tagged = KeyValuePair::with_metadata(
+            String::from("a"),
+            String::from("1"),
+            HashMap::from([(String::from("source"), String::from("import")), (String::from("version"), String::from("3"))]),
+        );
+        tagged.set_metadata(String::from("owner"), String::from("ops"));
+        assert_eq!(tagged.remove_metadata("version").as_deref(), Some("3"));
+        let plain = KeyValuePair::new(String::from("b"), String::from("2"));
+
+        let node = Node::new(NodeType::Leaf(vec![tagged, plain]), true, None);
+        let NodeType::Leaf(decoded) = Node::try_from(Page::try_from(&node)?)?.node_type else {
+            return Err(Error::UnexpectedError);
+        };
+        assert_eq!(decoded[0].get_metadata("source"), Some("import"));
+        assert_eq!(decoded[0].get_metadata("owner"), Some("ops"));
+        assert_eq!(decoded[0].get_metadata("version"), None);
+        assert_eq!(decoded[0].metadata.as_ref().map(HashMap::len), Some(2));
+        assert!(decoded[1].metadata.is_none());
+        assert_eq!((decoded[1].key.as_str(), decoded[1].value.as_str()), ("b", "2"));
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_nodes_larger_than_a_page() {
+        let pairs = (0..300).map(|i| KeyValuePair::new(format!("{:04}", i), String::from("value"))).collect();
+        let leaf = Node::new(NodeType::Leaf(pairs), true, None);
+        assert!(matches!(Page::try_from(&leaf), Err(Error::PageOverflowError)));
+
+        let mut tagged = KeyValuePair::new(String::from("a"), String::from("v"));
+        for i in 0..20 {
+            tagged.set_metadata(format!("{:02}", i), "m".repeat(200));
+        }
+        let leaf = Node::new(NodeType::Leaf(vec![tagged]), true, None);
+        assert!(matches!(Page::try_from(&leaf), Err(Error::PageOverflowError)));
+
+        let children = (0..300).map(|i| Offset(i * PAGE_SIZE)).collect();
+        let keys = (0..299).map(|i| Key(format!("{:04}", i))).collect();
+        let internal = Node::new(NodeType::Internal(children, keys), true, None);
+        assert!(matches!(Page::try_from(&internal), Err(Error::PageOverflowError)));
+    }
+
+    #[test]
+    fn should_fit_full_nodes_at_max_branches_per_page() -> Result<(), Error> {
+        // Reversed digits, so that neighbouring keys share no prefix.
+        let key = |i: usize| format!("{:0>10}", i).chars().rev().collect::<String>();
+        let full_leaf = |branches: usize| {
+            let pairs = (0..2 * branches - 1).map(|i| KeyValuePair::new(key(i), "v".repeat(VALUE_SIZE))).collect();
+            Page::try_from(&Node::new(NodeType::Leaf(pairs), true, None))
+        };
+        let full_internal = |branches: usize| {
+            let children = (0..2 * branches).map(|i| Offset(i * PAGE_SIZE)).collect();
+            let keys = (0..2 * branches - 1).map(|i| Key(key(i))).collect();
+            Page::try_from(&Node::new(NodeType::Internal(children, keys), true, None))
+        };
+
+        full_leaf(MAX_BRANCHES_PER_PAGE)?;
+        full_internal(MAX_BRANCHES_PER_PAGE)?;
+        assert!(full_leaf(MAX_BRANCHES_PER_PAGE + 1).is_err() || full_internal(MAX_BRANCHES_PER_PAGE + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_where_invalid_utf8_starts() -> Result<(), Error> {
+        let pairs = vec![KeyValuePair::new(String::from("abc"), String::from("v"))];
+        let mut data = Page::try_from(&Node::new(NodeType::Leaf(pairs), true, None))?.get_data();
+        let key_offset = LEAF_NODE_HEADER_SIZE + LEAF_KEY_SHARED_PREFIX_SIZE + LEAF_KEY_SUFFIX_LEN_SIZE;
+        assert_eq!(&data[key_offset..key_offset + 3], b"abc");
+        data[key_offset + 2] = 0xFF;
+
+        let Err(err) = Node::try_from(Page::new(data)) else {
+            panic!("expected the key to be rejected");
+        };
+        assert!(matches!(&err, Error::UTF8Error(e) if e.valid_up_to() == 2));
+        assert_eq!(err.to_string(), "invalid UTF-8 after 2 valid bytes");
+        Ok(())
+    }
+
+    #[test]
+    fn should_debug_format_header_fields() -> Result<(), Error> {
+        let mut node = Node::new(NodeType::Leaf(vec![KeyValuePair::new(String::from("a"), String::from("one"))]), false, Some(Offset(8192)));
+        let page = Page::try_from(&node)?;
+        let debug = format!("{:?}", page);
+        assert!(debug.contains(r#"magic: "BERS""#), "{}", debug);
+        assert!(debug.contains("is_root: false"), "{}", debug);
+        assert!(debug.contains("node_type: Leaf"), "{}", debug);
+        assert!(debug.contains("parent_offset: 8192"), "{}", debug);
+        assert!(debug.contains("count: 1"), "{}", debug);
+        assert!(debug.contains("data: 42 45 52 53 00 02"), "{}", debug);
+
+        node.is_root = true;
+        node.node_type = NodeType::Internal(vec![Offset(0), Offset(4096)], vec![Key(String::from("m"))]);
+        let debug = format!("{:?}", Page::try_from(&node)?);
+        assert!(debug.contains("is_root: true, node_type: Internal"), "{}", debug);
+        assert!(debug.contains("count: 2"), "{}", debug);
+
+        let mut data = [0x00; PAGE_SIZE];
+        data[NODE_TYPE_OFFSET] = 0x7f;
+        let debug = format!("{:?}", Page::new(data));
+        assert!(debug.contains("node_type: Unknown(0x7f)"), "{}", debug);
+        assert!(debug.contains("parent_offset: 0"), "{}", debug);
+        Ok(())
+    }
+
+    #[test]
+    fn should_patch_bytes_in_range_only() -> Result<(), Error> {
+        let mut page = Page::new([0x11; PAGE_SIZE]);
+        page.patch_bytes(100, &[1, 2, 3])?;
+        page.patch_bytes(PAGE_SIZE - 2, &[9, 9])?;
+
+        let data = page.get_data();
+        assert_eq!(&data[99..104], &[0x11, 1, 2, 3, 0x11]);
+        assert_eq!(&data[PAGE_SIZE - 3..], &[0x11, 9, 9]);
+        assert_eq!(data.iter().filter(|&&byte| byte != 0x11).count(), 5);
+
+        assert!(matches!(page.patch_bytes(PAGE_SIZE - 1, &[0, 0]), Err(Error::UnexpectedError)));
+        assert!(matches!(page.patch_bytes(usize::MAX, &[0]), Err(Error::UnexpectedError)));
+        assert_eq!(page.get_data(), data);
+        Ok(())
+    }
+}
@@ -1,19 +1,22 @@
 use std::{
-  fs::{File, OpenOptions},
-  io::{Read, Seek, SeekFrom, Write},
+  fs::{File, OpenOptions, TryLockError},
+  io::{self, IoSlice, Read, Seek, SeekFrom, Write},
+  os::unix::fs::FileExt,
   path::Path,
 };
 
 use crate::{
   error::Error,
   page::{Page, PAGE_SIZE},
-  node::Offset,
+  node::{Node, NodeType, Offset},
 };
 
 #[derive(Debug)]
 pub struct Pager {
   file: File,
   cursor: usize,
+  /// Sync the file after every page write instead of leaving it to `sync`.
+  sync_writes: bool,
 }
 
 impl Pager {
@@ -22,18 +25,92 @@ impl Pager {
       .create(true)
       .read(true)
       .write(true)
-      .truncate(true)
+      .truncate(false)
       .open(path)?;
 
     Ok(Self {
       file: fd,
       cursor: 0,
+      sync_writes: false,
     })
   }
 
+  pub fn open(path: &Path) -> Result<Self, Error> {
+    let fd = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open(path)?;
+    let cursor = fd.metadata()?.len() as usize;
+
+    Ok(Self {
+      file: fd,
+      cursor,
+      sync_writes: false,
+    })
+  }
+
+  /// Takes an exclusive `flock` on the data file without blocking.
+  pub fn lock_exclusive(&self) -> Result<(), Error> {
+    match self.file.try_lock() {
+      Ok(()) => Ok(()),
+      Err(TryLockError::WouldBlock) => Err(Error::DatabaseLocked),
+      Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+  }
+
+  /// The raw descriptor of the data file, for OS-level tools. It stays
+  /// owned by the pager and must not be closed.
+  pub fn file_descriptor_number(&self) -> std::os::unix::io::RawFd {
+    use std::os::unix::io::AsRawFd;
+    self.file.as_raw_fd()
+  }
+
+  pub fn unlock(&self) -> Result<(), Error> {
+    self.file.unlock()?;
+    Ok(())
+  }
+
+  pub fn truncate(&mut self) -> Result<(), Error> {
+    self.file.set_len(0)?;
+    self.cursor = 0;
+    Ok(())
+  }
+
+  pub fn sync(&self) -> Result<(), Error> {
+    self.file.sync_data()?;
+    Ok(())
+  }
+
+  pub fn set_sync_writes(&mut self, enabled: bool) {
+    self.sync_writes = enabled;
+  }
+
+  pub fn sync_writes(&self) -> bool {
+    self.sync_writes
+  }
+
+  fn sync_if_enabled(&self) -> Result<(), Error> {
+    if self.sync_writes {
+      self.sync()?;
+    }
+    Ok(())
+  }
+
+  /// Syncs the data file and copies its written pages to `dest_path`,
+  /// replacing any file there. Returns the number of bytes copied.
+  pub fn backup_to(&mut self, dest_path: impl AsRef<Path>) -> Result<u64, Error> {
+    self.sync()?;
+    let mut dest = File::create(dest_path)?;
+    self.file.seek(SeekFrom::Start(0))?;
+    let copied = io::copy(&mut (&self.file).take(self.cursor as u64), &mut dest)?;
+    dest.sync_all()?;
+    Ok(copied)
+  }
+
   pub fn write_page(&mut self, page: Page) -> Result<Offset, Error> {
     self.file.seek(SeekFrom::Start(self.cursor as u64))?;
     self.file.write_all(&page.get_data())?;
+    self.sync_if_enabled()?;
     let res = Offset(self.cursor);
     self.cursor += PAGE_SIZE;
     Ok(res)
@@ -42,13 +119,194 @@ impl Pager {
   pub fn write_page_at_offset(&mut self, page: Page, offset: &Offset) -> Result<(), Error> {
     self.file.seek(SeekFrom::Start(offset.0 as u64))?;
     self.file.write_all(&page.get_data())?;
+    self.sync_if_enabled()
+  }
+
+  /// Writes every page at its offset, in iteration order. A failed write
+  /// stops there with `Error::PageWriteError` naming its offset; the pages
+  /// written before it are kept, and undoing them is up to the caller.
+  pub fn write_all_pages<I>(&mut self, pages: I) -> Result<(), Error>
+  where
+    I: Iterator<Item = (Page, Offset)>,
+  {
+    for (page, offset) in pages {
+      let end = offset.0 + PAGE_SIZE;
+      self.write_page_at_offset(page, &offset).map_err(|_| Error::PageWriteError(offset.0))?;
+      self.cursor = self.cursor.max(end);
+    }
     Ok(())
   }
 
+  /// Writes every page at its offset, in offset order. Pages on adjacent
+  /// offsets are written with a single vectored write.
+  pub fn write_pages_batch(&mut self, mut pages: Vec<(Page, Offset)>) -> Result<(), Error> {
+    pages.sort_by_key(|(_, offset)| offset.0);
+    let data: Vec<_> = pages.iter().map(|(page, _)| page.get_data()).collect();
+
+    let mut start = 0;
+    while start < pages.len() {
+      let mut end = start + 1;
+      while end < pages.len() && pages[end].1.0 == pages[end - 1].1.0 + PAGE_SIZE {
+        end += 1;
+      }
+
+      let mut slices: Vec<_> = data[start..end].iter().map(|page| IoSlice::new(page)).collect();
+      let mut slices = &mut slices[..];
+      self.file.seek(SeekFrom::Start(pages[start].1.0 as u64))?;
+      while !slices.is_empty() {
+        let written = self.file.write_vectored(slices)?;
+        if written == 0 {
+          return Err(Error::UnexpectedError);
+        }
+        IoSlice::advance_slices(&mut slices, written);
+      }
+
+      self.cursor = self.cursor.max(pages[end - 1].1.0 + PAGE_SIZE);
+      start = end;
+    }
+    self.sync_if_enabled()
+  }
+
   pub fn get_page(&mut self, offset: &Offset) -> Result<Page, Error> {
+    self.read_page(offset)
+  }
+
+  /// Reads the page at `offset` with a positional read, so it only needs a
+  /// shared borrow of the pager.
+  pub fn read_page(&self, offset: &Offset) -> Result<Page, Error> {
+    Ok(Page::new(self.read_page_raw(offset)?))
+  }
+
+  /// Reads the `PAGE_SIZE` bytes at `offset` without wrapping them in a `Page`.
+  pub fn read_page_raw(&self, offset: &Offset) -> Result<[u8; PAGE_SIZE], Error> {
     let mut page: [u8; PAGE_SIZE] = [0x00; PAGE_SIZE];
-    self.file.seek(SeekFrom::Start(offset.0 as u64))?;
-    self.file.read_exact(&mut page)?;
-    Ok(Page::new(page))
+    self.file.read_exact_at(&mut page, offset.0 as u64)?;
+    Ok(page)
+  }
+
+  pub fn page_at_index(&mut self, n: usize) -> Result<Page, Error> {
+    self.get_page(&Offset(n * PAGE_SIZE))
+  }
+
+  pub fn page_count(&self) -> usize {
+    self.cursor / PAGE_SIZE
+  }
+
+  /// Height of the subtree at `offset`, found by following first children
+  /// down to a leaf. A leaf on its own has height 1.
+  pub fn node_height(pager: &mut Pager, offset: Offset) -> Result<usize, Error> {
+    let node = Node::try_from(pager.get_page(&offset)?)?;
+    match node.node_type {
+      NodeType::Internal(children, _) => {
+        let first_child = children.into_iter().next().ok_or(Error::UnexpectedError)?;
+        Ok(Pager::node_height(pager, first_child)? + 1)
+      }
+      NodeType::Leaf(_) => Ok(1),
+      NodeType::Unexpected => Err(Error::UnexpectedError),
+    }
   }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_filled_with(byte: u8) -> Page {
+        Page::new([byte; PAGE_SIZE])
+    }
+
+    #[test]
+    fn should_write_pages_batch_at_their_offsets() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join("btree-rs").join("should_write_pages_batch_at_their_offsets");
+        std::fs::create_dir_all(&dir)?;
+        let mut pager = Pager::new(&dir.join("db"))?;
+        pager.truncate()?;
+
+        pager.write_pages_batch(vec![
+            (page_filled_with(3), Offset(3 * PAGE_SIZE)),
+            (page_filled_with(0), Offset(0)),
+            (page_filled_with(1), Offset(PAGE_SIZE)),
+            (page_filled_with(5), Offset(5 * PAGE_SIZE)),
+        ])?;
+
+        assert_eq!(pager.page_count(), 6);
+        for n in [0, 1, 3, 5] {
+            assert_eq!(pager.page_at_index(n)?.get_data()[PAGE_SIZE - 1], n as u8);
+        }
+        assert_eq!(pager.write_page(page_filled_with(6))?.0, 6 * PAGE_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn should_read_raw_page_bytes() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join("btree-rs").join("should_read_raw_page_bytes");
+        std::fs::create_dir_all(&dir)?;
+        let mut pager = Pager::new(&dir.join("db"))?;
+        pager.truncate()?;
+        pager.write_page(page_filled_with(1))?;
+        let offset = pager.write_page(page_filled_with(2))?;
+
+        let raw = pager.read_page_raw(&offset)?;
+        assert_eq!(raw, pager.get_page(&offset)?.get_data());
+        assert!(raw.iter().all(|&byte| byte == 2));
+        assert!(pager.read_page_raw(&Offset(2 * PAGE_SIZE)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn should_back_up_written_pages() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join("btree-rs").join("should_back_up_written_pages");
+        std::fs::create_dir_all(&dir)?;
+        let mut pager = Pager::new(&dir.join("db"))?;
+        pager.truncate()?;
+        for byte in 0..3 {
+            pager.write_page(page_filled_with(byte))?;
+        }
+
+        assert_eq!(pager.backup_to(dir.join("backup"))?, 3 * PAGE_SIZE as u64);
+        assert_eq!(std::fs::read(dir.join("backup"))?, std::fs::read(dir.join("db"))?);
+        let backup = Pager::open(&dir.join("backup"))?;
+        assert_eq!(backup.page_count(), 3);
+        assert!(backup.read_page_raw(&Offset(2 * PAGE_SIZE))?.iter().all(|&byte| byte == 2));
+        Ok(())
+    }
+
+    #[test]
+    fn should_write_all_pages_from_iterator() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join("btree-rs").join("should_write_all_pages_from_iterator");
+        std::fs::create_dir_all(&dir)?;
+        let mut pager = Pager::new(&dir.join("db"))?;
+        pager.truncate()?;
+
+        pager.write_all_pages((0..100).rev().map(|n| (page_filled_with(n as u8), Offset(n * PAGE_SIZE))))?;
+
+        assert_eq!(pager.page_count(), 100);
+        for n in 0..100 {
+            assert!(pager.read_page_raw(&Offset(n * PAGE_SIZE))?.iter().all(|&byte| byte == n as u8));
+        }
+
+        let mut read_only = Pager { file: File::open(dir.join("db"))?, cursor: 0, sync_writes: false };
+        assert!(matches!(
+            read_only.write_all_pages(std::iter::once((page_filled_with(0), Offset(PAGE_SIZE)))),
+            Err(Error::PageWriteError(PAGE_SIZE))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn should_expose_open_file_descriptor() -> Result<(), Error> {
+        use std::os::fd::BorrowedFd;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir().join("btree-rs").join("should_expose_open_file_descriptor");
+        std::fs::create_dir_all(&dir)?;
+        let pager = Pager::new(&dir.join("db"))?;
+
+        let fd = pager.file_descriptor_number();
+        assert!(fd >= 0);
+        // Duplicating goes through fcntl, which fails on a closed descriptor.
+        let duplicate = File::from(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?);
+        assert_eq!(duplicate.metadata()?.ino(), std::fs::metadata(dir.join("db"))?.ino());
+        Ok(())
+    }
+}
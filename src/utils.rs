@@ -1,3 +1,8 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
 pub fn bool_to_byte(b: bool) -> u8 {
   if b { 0x01 } else { 0x00 }
 }
@@ -5,3 +10,73 @@ pub fn bool_to_byte(b: bool) -> u8 {
 pub fn byte_to_bool(b: u8) -> bool {
   b == 0x01
 }
+
+pub fn unix_timestamp() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+/// Whether `a` and `b` name the same existing directory.
+pub fn same_directory(a: &Path, b: &Path) -> bool {
+  match (a.canonicalize(), b.canonicalize()) {
+    (Ok(a), Ok(b)) => a == b,
+    _ => false,
+  }
+}
+
+/// CRC-32 (IEEE) of `bytes`, as used by zlib and Ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for byte in bytes {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+/// Appends `s` with a u32 length prefix.
+pub fn write_string(bytes: &mut Vec<u8>, s: &str) {
+  bytes.extend_from_slice(&(s.len() as u32).to_be_bytes());
+  bytes.extend_from_slice(s.as_bytes());
+}
+
+/// Cursor over a byte buffer written with big-endian integers and
+/// `write_string`.
+pub struct ByteReader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+  pub fn new(bytes: &'a [u8]) -> Self {
+    Self { bytes, position: 0 }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.position == self.bytes.len()
+  }
+
+  pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+    let bytes = self
+      .bytes
+      .get(self.position..self.position + len)
+      .ok_or(Error::UnexpectedError)?;
+    self.position += len;
+    Ok(bytes)
+  }
+
+  pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+    self.read_bytes(N)?
+      .try_into()
+      .map_err(|e: std::array::TryFromSliceError| Error::TryFromSliceError(e.to_string()))
+  }
+
+  pub fn read_string(&mut self) -> Result<String, Error> {
+    let len = u32::from_be_bytes(self.read_array()?) as usize;
+    Ok(std::str::from_utf8(self.read_bytes(len)?)?.to_string())
+  }
+}